@@ -14,145 +14,902 @@
 // git.rs - snowpatch git functionality
 //
 
-use git2::build::CheckoutBuilder;
-use git2::{Branch, Commit, Cred, Error, PushOptions, Remote, Repository};
+use git2::{
+    ApplyOptions, BranchType, Commit, Config, Cred, CredentialType, Delta, Diff, Error,
+    FetchOptions, Index, IndexEntry, IndexTime, Oid, Patch, PushOptions, Remote, RemoteCallbacks,
+    Repository, Signature, Time, Tree,
+};
 
+use std::fmt;
+use std::fs;
 use std::path::Path;
 use std::process::{Command, Output};
 use std::result::Result;
+use std::str;
 
-use settings::Git;
+use mbox::{self, MboxPatch};
+use settings::{self, CredentialMethod, Credentials, Git};
 
 pub static GIT_REF_BASE: &'static str = "refs/heads";
 
-pub fn get_latest_commit(repo: &Repository) -> Commit {
-    let head = repo.head().unwrap();
-    let oid = head.target().unwrap();
-    repo.find_commit(oid).unwrap()
+// Run git in the given directory, failing if it does
+fn run_git(dir: &Path, args: &[&str]) -> Result<Output, Error> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .map_err(|err| Error::from_str(&format!("Couldn't run git: {}", err)))?;
+    if output.status.success() {
+        Ok(output)
+    } else {
+        Err(Error::from_str(&format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// Make sure we have a bare mirror of `url` at `path`, cloning it if this is
+/// the first time, and that it has the remote we push test branches to.
+pub fn init_mirror(
+    url: &str,
+    path: &Path,
+    remote_name: &str,
+    remote_uri: &str,
+) -> Result<Repository, Error> {
+    if !path.exists() {
+        info!("Cloning {} into {}", url, path.display());
+        let parent = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(parent)
+            .map_err(|err| Error::from_str(&format!("Couldn't create {:?}: {}", parent, err)))?;
+        run_git(parent, &["clone", "--mirror", url, &path.to_string_lossy()])?;
+    }
+
+    let repo = Repository::open(path)?;
+    if repo.find_remote(remote_name).is_err() {
+        repo.remote(remote_name, remote_uri)?;
+    }
+    Ok(repo)
+}
+
+/// Bring a mirror made by `init_mirror` up to date with its upstream.
+pub fn update_mirror(repo: &Repository) -> Result<(), Error> {
+    debug!("Updating mirror {}", repo.path().display());
+    run_git(repo.path(), &["fetch", "origin"]).map(|_| ())
+}
+
+/// The commit to apply patches to for a base branch.  If the branch has an
+/// upstream like "origin/master", that's fetched first and the patches are
+/// applied to exactly the commit we fetched.  Otherwise the local branch is
+/// used as it is.
+pub fn base_commit(
+    repo: &Repository,
+    branch: &settings::Branch,
+    opts: &mut FetchOptions,
+) -> Result<Oid, Error> {
+    let upstream = match branch.upstream {
+        Some(ref upstream) => upstream,
+        None => {
+            let local = repo.find_branch(&branch.name, BranchType::Local)?;
+            return local
+                .get()
+                .target()
+                .ok_or_else(|| Error::from_str(&format!("Branch {} isn't a commit", branch.name)));
+        }
+    };
+
+    let mut parts = upstream.splitn(2, '/');
+    let (remote_name, remote_branch) = match (parts.next(), parts.next()) {
+        (Some(remote), Some(name)) if !remote.is_empty() && !name.is_empty() => (remote, name),
+        _ => {
+            return Err(Error::from_str(&format!(
+                "Upstream {} isn't of the form remote/branch",
+                upstream
+            )))
+        }
+    };
+    let tracking = format!("refs/remotes/{}/{}", remote_name, remote_branch);
+    let refspec = format!("+{}/{}:{}", GIT_REF_BASE, remote_branch, tracking);
+
+    debug!("Fetching {} for branch {}", upstream, branch.name);
+    repo.find_remote(remote_name)?
+        .fetch(&[&refspec], Some(opts), None)?;
+    repo.refname_to_id(&tracking)
 }
 
+/// The commit a series says it's based on, if it says and we have that
+/// commit.  `stated` is what it says anywhere other than the mbox of its
+/// patches, like in its cover letter, and is tried first.
+pub fn stated_base_commit(repo: &Repository, mbox: &Path, stated: Option<&str>) -> Option<Oid> {
+    let contents = fs::read(mbox).unwrap_or_default();
+    let contents = String::from_utf8_lossy(&contents);
+    let base = stated.or_else(|| mbox::base_commit(&contents))?;
+    match Oid::from_str(base).and_then(|base| repo.find_commit(base)) {
+        Ok(commit) => Some(commit.id()),
+        Err(_) => {
+            info!("Patches are based on {}, which we don't have", base);
+            None
+        }
+    }
+}
+
+/// Where test branches are pushed if a project doesn't say.
+pub static DEFAULT_REF_TEMPLATE: &'static str = "refs/heads/{name}_{branch}";
+
+/// What a test run is testing, which the refs pushed for it are named after.
+pub struct RefNames {
+    pub project: String,
+    pub patch_id: Option<u64>,
+    pub series_id: Option<u64>,
+    pub version: Option<u64>,
+    /// Identifies the run when nothing in the ref does
+    pub run: String,
+}
+
+impl RefNames {
+    /// The ref to push a test branch for `branch` to, from a template like
+    /// `refs/snowpatch/{project}/{series_id}/v{version}/{branch}`.  `name` is
+    /// the name of the mbox the patches came from.
+    ///
+    /// Refs must be different for every run, so if the template doesn't name
+    /// the patch or series being tested, the run is added to the end.
+    pub fn expand(&self, template: &str, name: &str, branch: &str) -> String {
+        let unique = match (self.patch_id, self.series_id) {
+            (Some(_), _) => template.contains("{patch_id}"),
+            (None, Some(_)) => template.contains("{series_id}"),
+            (None, None) => false,
+        };
+        let mut refname = template
+            .replace("{project}", &self.project)
+            .replace("{patch_id}", &self.patch_id.unwrap_or(0).to_string())
+            .replace("{series_id}", &self.series_id.unwrap_or(0).to_string())
+            .replace("{version}", &self.version.unwrap_or(1).to_string())
+            .replace("{name}", name)
+            .replace("{branch}", branch);
+        if !unique {
+            refname.push('_');
+            refname.push_str(&self.run);
+        }
+        refname
+    }
+}
+
+/// Push a commit to a ref on a remote, through a local ref of the same name
+/// that's only needed until the push is done.
 pub fn push_to_remote(
+    repo: &Repository,
+    remote: &mut Remote,
+    commit: Oid,
+    refname: &str,
+    mut opts: &mut PushOptions,
+) -> Result<(), Error> {
+    let mut reference = repo.reference(refname, commit, true, "snowpatch test branch")?;
+    let refspecs: &[&str] = &[&format!("+{0}:{0}", refname)];
+    let result = remote.push(refspecs, Some(&mut opts));
+    // What matters is whether the push worked, as that's what has to be
+    // cleaned up later; a leftover local ref is only replaced next time
+    if let Err(err) = reference.delete() {
+        warn!("Couldn't delete local ref {}: {}", refname, err);
+    }
+    result
+}
+
+pub fn delete_remote_ref(
     remote: &mut Remote,
-    branch: &Branch,
-    delete: bool,
+    refname: &str,
     mut opts: &mut PushOptions,
 ) -> Result<(), Error> {
-    let action = if delete { ":" } else { "+" };
-    let refspecs: &[&str] = &[&format!(
-        "{}{}/{}",
-        action,
-        GIT_REF_BASE,
-        branch.name().unwrap().unwrap()
-    )];
+    // Runs recorded before refs could go anywhere only have a branch name
+    let refname = if refname.starts_with("refs/") {
+        refname.to_string()
+    } else {
+        format!("{}/{}", GIT_REF_BASE, refname)
+    };
+    let refspecs: &[&str] = &[&format!(":{}", refname)];
     remote.push(refspecs, Some(&mut opts))
 }
 
-// TODO: rewrite this to use git2-rs, I think it's impossible currently
-pub fn pull(repo: &Repository) -> Result<Output, &'static str> {
-    let workdir = repo.workdir().unwrap(); // TODO: support bare repositories
+/// A file in a patch that didn't apply.
+#[derive(Debug)]
+pub struct FileConflict {
+    pub path: String,
+    pub reason: String,
+    /// The hunks that were rejected, counting from 1, as they appear in the
+    /// patch
+    pub hunks: Vec<(usize, String)>,
+}
 
-    let output = Command::new("git")
-        .arg("pull") // pull the cool kid's way
-        .current_dir(&workdir) // in the repo's working directory
-        .output() // run synchronously
-        .unwrap(); // TODO
+impl fmt::Display for FileConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.reason)?;
+        if !self.hunks.is_empty() {
+            let hunks: Vec<String> = self.hunks.iter().map(|hunk| hunk.0.to_string()).collect();
+            let plural = if hunks.len() > 1 { "s" } else { "" };
+            write!(f, " (hunk{} {})", plural, hunks.join(", "))?;
+        }
+        Ok(())
+    }
+}
 
-    if output.status.success() {
-        debug!(
-            "Pull: {}",
-            String::from_utf8(output.clone().stdout).unwrap()
-        );
-        Ok(output)
-    } else {
-        Err("Error: couldn't pull changes")
+/// Why some patches didn't apply.
+#[derive(Debug)]
+pub struct ApplyError {
+    /// Which patch in the mbox didn't apply, counting from 1, or 0 if we
+    /// couldn't get that far
+    pub patch: usize,
+    pub subject: String,
+    /// The files that didn't apply
+    pub files: Vec<FileConflict>,
+    /// Why, if it's not down to particular files
+    pub reason: Option<String>,
+}
+
+impl ApplyError {
+    /// Everything we know about why the patches didn't apply, including the
+    /// text of each rejected hunk.  The most useful parts come first, so it
+    /// can be cut short.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        if self.patch > 0 {
+            report.push_str(&format!(
+                "Patch {} (\"{}\") failed to apply.\n\n",
+                self.patch, self.subject
+            ));
+        }
+        if let Some(ref reason) = self.reason {
+            report.push_str(&format!("{}\n", reason));
+        }
+        for file in &self.files {
+            report.push_str(&format!("{}\n", file));
+        }
+        for file in &self.files {
+            for &(number, ref text) in &file.hunks {
+                report.push_str(&format!(
+                    "\nRejected hunk {} of {}:\n{}",
+                    number, file.path, text
+                ));
+            }
+        }
+        report
     }
 }
 
-pub fn checkout_branch(repo: &Repository, branch: &str) -> () {
-    let workdir = repo.workdir().unwrap(); // TODO: support bare repositories
+impl fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.patch > 0 {
+            write!(f, "Patch {} (\"{}\"): ", self.patch, self.subject)?;
+        }
+        if let Some(ref reason) = self.reason {
+            return write!(f, "{}", reason);
+        }
+        let files: Vec<String> = self.files.iter().map(FileConflict::to_string).collect();
+        write!(f, "{}", files.join("; "))
+    }
+}
 
-    // Make sure there's no junk lying around before we switch
-    Command::new("git")
-        .arg("reset")
-        .arg("--hard")
-        .current_dir(&workdir)
-        .output()
-        .unwrap();
+// What went wrong applying one patch
+enum Failure {
+    // Not down to any one file
+    Reason(String),
+    Files(Vec<FileConflict>),
+}
 
-    Command::new("git")
-        .arg("clean")
-        .arg("-f") // force remove files we don't need
-        .arg("-d") // ...including directories
-        .current_dir(&workdir)
-        .output()
-        .unwrap();
-
-    repo.set_head(&format!("{}/{}", GIT_REF_BASE, &branch))
-        .unwrap_or_else(|err| panic!("Couldn't set HEAD: {}", err));
-    repo.checkout_head(Some(&mut CheckoutBuilder::new().force()))
-        .unwrap_or_else(|err| panic!("Couldn't checkout HEAD: {}", err));
-
-    // Clean up again, just to be super sure
-    Command::new("git")
-        .arg("reset")
-        .arg("--hard")
-        .current_dir(&workdir)
-        .output()
-        .unwrap();
+impl Failure {
+    // A file that didn't apply, and which of its hunks were rejected
+    fn file(diff: &Diff, delta: usize, hunks: &[usize], reason: &str) -> Failure {
+        let path = diff
+            .get_delta(delta)
+            .and_then(|delta| delta.new_file().path().or_else(|| delta.old_file().path()))
+            .map_or_else(String::new, |path| path.to_string_lossy().into_owned());
+        let patch = Patch::from_diff(diff, delta).ok().and_then(|patch| patch);
+        Failure::Files(vec![FileConflict {
+            path: path,
+            reason: reason.to_string(),
+            hunks: hunks
+                .iter()
+                .map(|&hunk| {
+                    let text = patch
+                        .as_ref()
+                        .map_or_else(String::new, |patch| hunk_text(patch, hunk));
+                    (hunk + 1, text)
+                })
+                .collect(),
+        }])
+    }
+}
 
-    Command::new("git")
-        .arg("clean")
-        .arg("-f") // force remove files we don't need
-        .arg("-d") // ...including directories
-        .current_dir(&workdir)
-        .output()
-        .unwrap();
-    ()
+impl From<Error> for Failure {
+    fn from(err: Error) -> Failure {
+        Failure::Reason(err.message().to_string())
+    }
 }
 
-pub fn apply_patch(repo: &Repository, path: &Path) -> Result<Output, &'static str> {
-    let workdir = repo.workdir().unwrap(); // TODO: support bare repositories
+// A hunk as it appears in the diff, for reporting when it doesn't apply
+fn hunk_text(patch: &Patch, hunk: usize) -> String {
+    let mut text = Vec::new();
+    if let Ok((header, lines)) = patch.hunk(hunk) {
+        text.extend_from_slice(header.header());
+        for line in (0..lines).filter_map(|line| patch.line_in_hunk(hunk, line).ok()) {
+            // Lines saying there's no newline at the end of the file have
+            // everything they need in their content
+            match line.origin() {
+                origin @ ' ' | origin @ '+' | origin @ '-' => text.push(origin as u8),
+                _ => (),
+            }
+            text.extend_from_slice(line.content());
+        }
+    }
+    String::from_utf8_lossy(&text).into_owned()
+}
 
-    // We call out to "git am" since libgit2 doesn't implement "am"
-    let output = Command::new("git")
-        .arg("am") // apply from mbox
-        .arg("-3") // three way merge
-        .arg(&path) // from our mbox file
-        .current_dir(&workdir) // in the repo's working directory
-        .output() // run synchronously
-        .unwrap(); // TODO
+fn index_entry(path: &str, id: Oid, mode: u32, size: usize) -> IndexEntry {
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode: mode,
+        uid: 0,
+        gid: 0,
+        file_size: size as u32,
+        id: id,
+        flags: 0,
+        flags_extended: 0,
+        path: path.as_bytes().to_vec(),
+    }
+}
 
-    if output.status.success() {
-        debug!(
-            "Patch applied with text {}",
-            String::from_utf8(output.clone().stdout).unwrap()
-        );
-        Ok(output)
+// A copy of a tree with one file's contents replaced
+fn replace_blob<'a>(
+    repo: &'a Repository,
+    tree: &Tree,
+    path: &str,
+    content: &[u8],
+    mode: u32,
+) -> Result<Tree<'a>, Error> {
+    let mut index = Index::new()?;
+    index.read_tree(tree)?;
+    index.add(&index_entry(path, repo.blob(content)?, mode, content.len()))?;
+    repo.find_tree(index.write_tree_to(repo)?)
+}
+
+// Apply the changes a diff makes to one file to a tree, or just one of its
+// hunks
+fn apply_delta<'a>(
+    repo: &'a Repository,
+    tree: &Tree,
+    diff: &Diff,
+    delta: usize,
+    hunk: Option<usize>,
+) -> Result<Tree<'a>, Error> {
+    let mut deltas = 0;
+    let mut hunks = 0;
+    let mut options = ApplyOptions::new();
+    options.delta_callback(move |_| {
+        deltas += 1;
+        deltas - 1 == delta
+    });
+    // Only the hunks of the delta we apply are offered
+    options.hunk_callback(move |_| {
+        hunks += 1;
+        hunk.map_or(true, |hunk| hunks - 1 == hunk)
+    });
+    let mut index = repo.apply_to_tree(tree, diff, Some(&mut options))?;
+    repo.find_tree(index.write_tree_to(repo)?)
+}
+
+// The ID of the version of a file a patch was made against, as it's given,
+// usually abbreviated, on the "index" line of the file's part of the diff.
+// libgit2 pads abbreviated IDs out with zeros, so only the text says how
+// much of the ID there is.
+fn old_id_prefix<'a>(text: &'a [u8], path: &str) -> Option<&'a str> {
+    let header = format!("diff --git a/{} b/", path);
+    let mut in_file = false;
+    for line in text.split(|&byte| byte == b'\n') {
+        if line.starts_with(b"diff --git ") {
+            in_file = line.starts_with(header.as_bytes());
+        } else if in_file && line.starts_with(b"index ") {
+            return str::from_utf8(&line[6..])
+                .ok()
+                .and_then(|ids| ids.split("..").next());
+        }
+    }
+    None
+}
+
+// If a file's hunks don't apply, apply them to the version of the file the
+// patch was made against, if we have it, and merge in the changes made
+// since, like "git am -3" does
+fn three_way_merge<'a>(
+    repo: &'a Repository,
+    ours: &Tree,
+    diff: &Diff,
+    text: &[u8],
+    delta: usize,
+    hunks: &[usize],
+) -> Result<Tree<'a>, Failure> {
+    let doesnt_apply = || Failure::file(diff, delta, hunks, "doesn't apply");
+    let path = {
+        let file = diff.get_delta(delta).ok_or_else(doesnt_apply)?.old_file();
+        let path = file.path().ok_or_else(doesnt_apply)?;
+        path.to_string_lossy().into_owned()
+    };
+    let prefix = old_id_prefix(text, &path).ok_or_else(doesnt_apply)?;
+    let original = Oid::from_str(prefix)
+        .and_then(|id| repo.odb()?.exists_prefix(id, prefix.len()))
+        .and_then(|id| repo.find_blob(id))
+        .map_err(|_| doesnt_apply())?;
+    let mode = ours
+        .get_path(Path::new(&path))
+        .map(|entry| entry.filemode() as u32)
+        .unwrap_or(0o100_644);
+
+    let ancestor = replace_blob(repo, ours, &path, original.content(), mode)?;
+    let theirs = apply_delta(repo, &ancestor, diff, delta, None).map_err(|_| doesnt_apply())?;
+    let mut merged = repo.merge_trees(&ancestor, ours, &theirs, None)?;
+    if merged.has_conflicts() {
+        return Err(Failure::file(
+            diff,
+            delta,
+            hunks,
+            "conflicts with changes made since the patch was written",
+        ));
+    }
+    debug!("Applied {} with a three way merge", path);
+    Ok(repo.find_tree(merged.write_tree_to(repo)?)?)
+}
+
+// Apply the changes to one file to a tree, working out why they don't apply
+// if they don't
+fn apply_file<'a>(
+    repo: &'a Repository,
+    tree: &Tree,
+    diff: &Diff,
+    text: &[u8],
+    delta: usize,
+) -> Result<Tree<'a>, Failure> {
+    if let Some(file) = diff.get_delta(delta) {
+        let exists = |path: Option<&Path>| path.map_or(false, |path| tree.get_path(path).is_ok());
+        if file.status() == Delta::Added && exists(file.new_file().path()) {
+            return Err(Failure::file(diff, delta, &[], "already exists"));
+        }
+        if file.status() != Delta::Added && !exists(file.old_file().path()) {
+            return Err(Failure::file(diff, delta, &[], "doesn't exist"));
+        }
+    }
+    if let Ok(tree) = apply_delta(repo, tree, diff, delta, None) {
+        return Ok(tree);
+    }
+    let hunks = Patch::from_diff(diff, delta)?.map_or(0, |patch| patch.num_hunks());
+    let rejected: Vec<usize> = (0..hunks)
+        .filter(|&hunk| apply_delta(repo, tree, diff, delta, Some(hunk)).is_err())
+        .collect();
+    three_way_merge(repo, tree, diff, text, delta, &rejected)
+}
+
+// Commit a patch on top of `parent`
+fn commit_patch(
+    repo: &Repository,
+    parent: &Commit,
+    patch: &MboxPatch,
+    committer: &Signature,
+) -> Result<Oid, Failure> {
+    let diff = Diff::from_buffer(&patch.diff)?;
+    let mut tree = parent.tree()?;
+    if let Ok(mut index) = repo.apply_to_tree(&tree, &diff, None) {
+        tree = repo.find_tree(index.write_tree_to(repo)?)?;
     } else {
-        info!(
-            "Patch failed to apply with text {} {}",
-            String::from_utf8(output.clone().stdout).unwrap(),
-            String::from_utf8(output.clone().stderr).unwrap()
-        );
-        Command::new("git")
-            .arg("am")
-            .arg("--abort")
-            .current_dir(&workdir)
-            .output()
-            .unwrap();
-        Err("Patch did not apply successfully")
+        // Go a file at a time, carrying on past files that don't apply so
+        // we can say about all of them
+        let mut conflicts = Vec::new();
+        for delta in 0..diff.deltas().len() {
+            match apply_file(repo, &tree, &diff, &patch.diff, delta) {
+                Ok(applied) => tree = applied,
+                Err(Failure::Files(mut files)) => conflicts.append(&mut files),
+                Err(failure) => return Err(failure),
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(Failure::Files(conflicts));
+        }
+    }
+
+    let author = match patch.date {
+        Some((time, offset)) => Signature::new(
+            &patch.author_name,
+            &patch.author_email,
+            &Time::new(time, offset),
+        )?,
+        None => Signature::now(&patch.author_name, &patch.author_email)?,
+    };
+    Ok(repo.commit(None, &author, committer, &patch.message, &tree, &[parent])?)
+}
+
+/// Apply the patches in an mbox on top of a commit, committing each one,
+/// and return the commits in order, so the last one has every patch.
+/// Everything happens in the repository's object database, so no working
+/// tree is needed.
+pub fn apply_mbox(repo: &Repository, base: Oid, path: &Path) -> Result<Vec<Oid>, ApplyError> {
+    let error = |patch: usize, subject: &str, failure: Failure| {
+        let (files, reason) = match failure {
+            Failure::Reason(reason) => (vec![], Some(reason)),
+            Failure::Files(files) => (files, None),
+        };
+        ApplyError {
+            patch: patch,
+            subject: subject.to_string(),
+            files: files,
+            reason: reason,
+        }
+    };
+    let contents = fs::read(path).map_err(|err| {
+        let reason = format!("Couldn't read {}: {}", path.display(), err);
+        error(0, "", Failure::Reason(reason))
+    })?;
+    let patches = mbox::parse(&contents);
+    if patches.is_empty() {
+        let reason = "No patches found".to_string();
+        return Err(error(0, "", Failure::Reason(reason)));
+    }
+
+    // Repositories snowpatch manages don't have an identity configured
+    let committer = repo
+        .signature()
+        .or_else(|_| Signature::now("snowpatch", "snowpatch@localhost"))
+        .map_err(|err| error(0, "", err.into()))?;
+    let mut head = repo
+        .find_commit(base)
+        .map_err(|err| error(0, "", err.into()))?;
+    let mut commits = Vec::new();
+    for (i, patch) in patches.iter().enumerate() {
+        let commit = commit_patch(repo, &head, patch, &committer)
+            .and_then(|commit| Ok(repo.find_commit(commit)?))
+            .map_err(|failure| error(i + 1, &patch.subject, failure))?;
+        debug!("Applied patch {} as {}", patch.subject, commit.id());
+        commits.push(commit.id());
+        head = commit;
     }
+    Ok(commits)
 }
 
-pub fn cred_from_settings(settings: &Git) -> Result<Cred, Error> {
+// Credentials for a remote, with the username from its URL if we haven't
+// been given one
+fn cred(credentials: &Credentials, url: &str, url_user: Option<&str>) -> Result<Cred, Error> {
     // We have to convert from Option<String> to Option<&str>
-    let public_key = settings.public_key.as_ref().map(String::as_ref);
-    let passphrase = settings.passphrase.as_ref().map(String::as_ref);
-
-    Cred::ssh_key(
-        &settings.user,
-        public_key,
-        Path::new(&settings.private_key),
-        passphrase,
-    )
+    let user = credentials.user.as_ref().map(String::as_str).or(url_user);
+    let need_user = || user.ok_or_else(|| Error::from_str(&format!("No git user for {}", url)));
+    let public_key = credentials.public_key.as_ref().map(String::as_ref);
+    let passphrase = credentials.passphrase.as_ref().map(String::as_ref);
+
+    match credentials.method() {
+        CredentialMethod::SshKey => {
+            let private_key = credentials
+                .private_key
+                .as_ref()
+                .ok_or_else(|| Error::from_str(&format!("No private key for {}", url)))?;
+            Cred::ssh_key(need_user()?, public_key, Path::new(private_key), passphrase)
+        }
+        CredentialMethod::SshAgent => Cred::ssh_key_from_agent(need_user()?),
+        CredentialMethod::UserPass => {
+            let password = credentials.password.as_ref().map_or("", String::as_str);
+            Cred::userpass_plaintext(need_user()?, password)
+        }
+        CredentialMethod::Helper => Cred::credential_helper(&Config::open_default()?, url, user),
+    }
+}
+
+/// Callbacks that authenticate to remotes with the project's credentials if
+/// it has its own, or otherwise the ones configured for the remote's URL.
+///
+/// libgit2 asks again whenever credentials are rejected, so we give up after
+/// the first try rather than asking forever.
+pub fn remote_callbacks<'a>(
+    settings: &'a Git,
+    project: Option<&'a Credentials>,
+) -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    let mut tried = false;
+    callbacks.credentials(move |url, url_user, allowed| {
+        let credentials = project.unwrap_or_else(|| settings.credentials_for(url));
+        // For SSH URLs without a username, libgit2 asks for one first
+        if allowed.contains(CredentialType::USERNAME) {
+            let user = credentials.user.as_ref().map_or("git", String::as_str);
+            return Cred::username(user);
+        }
+        if tried {
+            return Err(Error::from_str(&format!(
+                "Credentials for {} were rejected",
+                url
+            )));
+        }
+        tried = true;
+        cred(credentials, url, url_user)
+    });
+    callbacks
+}
+
+#[cfg(test)]
+mod test {
+    use git::*;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+
+    // A repository with a commit with these files, and the commit's ID
+    fn test_repo(dir: &Path, files: &[(&str, &[u8])]) -> (Repository, Oid) {
+        let repo = Repository::init_bare(dir).unwrap();
+        let base = {
+            let mut tree = repo.find_tree(Index::new().unwrap().write_tree_to(&repo).unwrap());
+            for &(path, content) in files {
+                tree = replace_blob(&repo, &tree.unwrap(), path, content, 0o100_644);
+            }
+            let tree = tree.unwrap();
+            let signature = Signature::now("snowpatch", "snowpatch@localhost").unwrap();
+            repo.commit(None, &signature, &signature, "base", &tree, &[])
+                .unwrap()
+        };
+        (repo, base)
+    }
+
+    fn write_mbox(dir: &Path, mbox: &[u8]) -> PathBuf {
+        let path = dir.join("patches.mbox");
+        File::create(&path).unwrap().write_all(mbox).unwrap();
+        path
+    }
+
+    fn blob(repo: &Repository, commit: Oid, path: &str) -> Vec<u8> {
+        let tree = repo.find_commit(commit).unwrap().tree().unwrap();
+        let entry = tree.get_path(Path::new(path)).unwrap();
+        repo.find_blob(entry.id()).unwrap().content().to_vec()
+    }
+
+    static LOGO: &'static [u8] = b"\x00\x01\x02\x03snowpatch\x00";
+
+    #[test]
+    fn ref_names() {
+        let names = RefNames {
+            project: "linuxppc-dev".to_string(),
+            patch_id: Some(1234),
+            series_id: Some(56),
+            version: Some(2),
+            run: "patch_1234".to_string(),
+        };
+        assert_eq!(
+            names.expand(
+                "refs/snowpatch/{project}/{patch_id}/{branch}",
+                "foo",
+                "next"
+            ),
+            "refs/snowpatch/linuxppc-dev/1234/next"
+        );
+        // Other patches in the series would get the same ref
+        assert_eq!(
+            names.expand(
+                "refs/snowpatch/{series_id}/v{version}/{branch}",
+                "foo",
+                "next"
+            ),
+            "refs/snowpatch/56/v2/next_patch_1234"
+        );
+        assert_eq!(
+            names.expand(DEFAULT_REF_TEMPLATE, "foo_mbox", "master"),
+            "refs/heads/foo_mbox_master_patch_1234"
+        );
+    }
+
+    #[test]
+    fn apply_error_report() {
+        let err = ApplyError {
+            patch: 2,
+            subject: "foo: fix bar".to_string(),
+            files: vec![
+                FileConflict {
+                    path: "foo.c".to_string(),
+                    reason: "doesn't apply".to_string(),
+                    hunks: vec![(3, "@@ -1 +1 @@\n-bar\n+baz\n".to_string())],
+                },
+                FileConflict {
+                    path: "foo.h".to_string(),
+                    reason: "doesn't exist".to_string(),
+                    hunks: vec![],
+                },
+            ],
+            reason: None,
+        };
+        assert_eq!(
+            err.to_string(),
+            "Patch 2 (\"foo: fix bar\"): foo.c: doesn't apply (hunk 3); foo.h: doesn't exist"
+        );
+        assert_eq!(
+            err.report(),
+            "Patch 2 (\"foo: fix bar\") failed to apply.\n\n\
+             foo.c: doesn't apply (hunk 3)\n\
+             foo.h: doesn't exist\n\
+             \n\
+             Rejected hunk 3 of foo.c:\n\
+             @@ -1 +1 @@\n-bar\n+baz\n"
+        );
+    }
+
+    #[test]
+    fn apply_patches() {
+        let dir = TempDir::new("snowpatch").unwrap();
+        let (repo, base) = test_repo(
+            &dir.path().join("repo"),
+            &[("hello.c", b"hello\n"), ("logo.bin", LOGO)],
+        );
+        let mbox = write_mbox(
+            dir.path(),
+            b"\
+From 60cc43fc888428bb2f18f08997432d426a243338 Mon Sep 17 00:00:00 2001
+From: Andre Example <andre@example.com>
+Date: Wed, 11 Jul 2018 14:21:05 +1000
+Subject: [PATCH 1/2] hello: say hello to the world
+
+---
+diff --git a/hello.c b/hello.c
+index ce01362..4b5fa63 100644
+--- a/hello.c
++++ b/hello.c
+@@ -1 +1 @@
+-hello
++hello, world
+-- 
+2.17.1
+
+From e3f67fc361d274dfe0a90f69872059ac2a98b680 Mon Sep 17 00:00:00 2001
+From: Andre Example <andre@example.com>
+Date: Wed, 11 Jul 2018 14:21:06 +1000
+Subject: [PATCH 2/2] Make the logo louder
+
+---
+diff --git a/logo.bin b/logo.bin
+index 33acb6af0229a892810581d027ebc576a97f2bde..dd41967ee54da613f1acdc0aded48a4814169b8a 100644
+GIT binary patch
+literal 15
+WcmZQzWMV1K%P%iTEJ@B#WB>pg;{*Tz
+
+literal 14
+VcmZQzWMVGP%P%iTEJ@B_000-a1Kt1t
+
+-- 
+2.17.1
+",
+        );
+
+        let commits = apply_mbox(&repo, base, &mbox).unwrap();
+        assert_eq!(commits.len(), 2);
+        let head = commits[1];
+        assert_eq!(blob(&repo, head, "hello.c"), b"hello, world\n");
+        assert_eq!(
+            blob(&repo, head, "logo.bin"),
+            b"\x00\x01\x02\x04snowpatch!\x00"
+        );
+        let commit = repo.find_commit(head).unwrap();
+        assert_eq!(commit.message(), Some("Make the logo louder\n"));
+        assert_eq!(commit.author().name(), Some("Andre Example"));
+        assert_eq!(commit.parent_id(0).unwrap(), commits[0]);
+        let first = repo.find_commit(commits[0]).unwrap();
+        assert_eq!(first.summary(), Some("hello: say hello to the world"));
+        assert_eq!(first.parent_id(0).unwrap(), base);
+    }
+
+    #[test]
+    fn old_id_prefixes() {
+        let text = b"\
+diff --git a/hello.c b/hello.c
+index 1a20000..4b5fa63 100644
+--- a/hello.c
++++ b/hello.c
+diff --git a/logo.bin b/logo.bin
+index 33acb6a..dd41967 100644
+";
+        // Trailing zeros are part of the ID
+        assert_eq!(old_id_prefix(text, "hello.c"), Some("1a20000"));
+        assert_eq!(old_id_prefix(text, "logo.bin"), Some("33acb6a"));
+        assert_eq!(old_id_prefix(text, "hello.h"), None);
+    }
+
+    #[test]
+    fn apply_three_way() {
+        let dir = TempDir::new("snowpatch").unwrap();
+        let original = b"one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\n";
+        // The line changed since is in the patch's context
+        let (repo, base) = test_repo(
+            &dir.path().join("repo"),
+            &[(
+                "count.txt",
+                b"one\nTWO\nthree\nfour\nfive\nsix\nseven\neight\nnine\n",
+            )],
+        );
+        let original_id = repo.blob(original).unwrap().to_string();
+        let mbox = write_mbox(
+            dir.path(),
+            format!(
+                "\
+From 60cc43fc888428bb2f18f08997432d426a243338 Mon Sep 17 00:00:00 2001
+From: Andre Example <andre@example.com>
+Subject: [PATCH] Count in French
+
+diff --git a/count.txt b/count.txt
+index {}..1234567 100644
+--- a/count.txt
++++ b/count.txt
+@@ -2,7 +2,7 @@
+ two
+ three
+ four
+-five
++cinq
+ six
+ seven
+ eight
+",
+                &original_id[..7]
+            )
+            .as_bytes(),
+        );
+
+        let commits = apply_mbox(&repo, base, &mbox).unwrap();
+        assert_eq!(
+            blob(&repo, commits[0], "count.txt"),
+            b"one\nTWO\nthree\nfour\ncinq\nsix\nseven\neight\nnine\n"
+        );
+    }
+
+    #[test]
+    fn apply_conflicts() {
+        let dir = TempDir::new("snowpatch").unwrap();
+        let (repo, base) = test_repo(
+            &dir.path().join("repo"),
+            &[(
+                "hello.c",
+                b"one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\n",
+            )],
+        );
+        let mbox = write_mbox(
+            dir.path(),
+            b"\
+From 60cc43fc888428bb2f18f08997432d426a243338 Mon Sep 17 00:00:00 2001
+From: Andre Example <andre@example.com>
+Subject: [PATCH] Count in French
+
+diff --git a/hello.c b/hello.c
+--- a/hello.c
++++ b/hello.c
+@@ -1,2 +1,2 @@
+-one
++un
+ two
+@@ -8,2 +8,2 @@
+ eight
+-ten
++dix
+diff --git a/missing.c b/missing.c
+--- a/missing.c
++++ b/missing.c
+@@ -1 +1 @@
+-gone
++still gone
+",
+        );
+
+        let err = apply_mbox(&repo, base, &mbox).unwrap_err();
+        assert_eq!(err.patch, 1);
+        assert_eq!(err.subject, "Count in French");
+        assert_eq!(err.files.len(), 2);
+        assert_eq!(err.files[0].path, "hello.c");
+        assert_eq!(err.files[0].reason, "doesn't apply");
+        assert_eq!(
+            err.files[0].hunks,
+            vec![(2, "@@ -8,2 +8,2 @@\n eight\n-ten\n+dix\n".to_string())]
+        );
+        assert_eq!(err.files[1].path, "missing.c");
+        assert_eq!(err.files[1].reason, "doesn't exist");
+    }
 }
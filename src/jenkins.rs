@@ -17,26 +17,30 @@
 // TODO:
 // * get Jenkins config details from somewhere
 // * get status for the build
-// * get artifacts + console log from completed build (do we make this configurable?)
+// * get artifacts from completed build (do we make this configurable?)
 // * integrate into snowpatch worker thread
 
 extern crate reqwest;
 extern crate url;
 
-use std::collections::BTreeMap;
-use std::io::Read;
-use std::sync::Arc;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::Duration;
 
-use reqwest::header::{Authorization, Basic, Headers, Location};
-use reqwest::{Client, IntoUrl, Response};
+use regex::Regex;
+use reqwest::header::{Authorization, Basic, Cookie, Headers, Location, SetCookie};
+use reqwest::{Client, IntoUrl, Response, StatusCode};
 use serde_json::{self, Value};
 
 use patchwork::TestState;
+use utils;
 
 // Constants
 const JENKINS_POLLING_INTERVAL: u64 = 5000; // Polling interval in milliseconds
+const JENKINS_API_RETRIES: u32 = 60; // Retries of an API request, five minutes' worth
 
 // Jenkins API definitions
 
@@ -51,6 +55,36 @@ pub struct JenkinsBackend {
     pub reqwest_client: Arc<Client>,
     pub username: Option<String>,
     pub token: Option<String>,
+    crumb: Mutex<CrumbState>,
+}
+
+// A token Jenkins wants sent with every POST when CSRF protection is on,
+// along with the cookies for the session it was issued to
+#[derive(Clone)]
+struct Crumb {
+    field: String,
+    value: String,
+    cookies: Vec<(String, String)>,
+}
+
+enum CrumbState {
+    // We haven't asked yet
+    Unknown,
+    // CSRF protection is off
+    NotNeeded,
+    Issued(Crumb),
+}
+
+// The name and value of a cookie from a Set-Cookie header
+fn parse_cookie(set_cookie: &str) -> Option<(String, String)> {
+    let cookie = set_cookie.split(';').next()?;
+    let mut parts = cookie.splitn(2, '=');
+    let name = parts.next()?.trim();
+    let value = parts.next()?.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((name.to_string(), value.to_string()))
 }
 
 impl CIBackend for JenkinsBackend {
@@ -80,13 +114,190 @@ impl CIBackend for JenkinsBackend {
     }
 }
 
+/// The interesting part of a build's console output.
+#[derive(Debug, PartialEq)]
+pub enum ConsoleExcerpt {
+    /// The lines matching the patterns we were looking for
+    Matching(String),
+    /// The last lines, as none matched
+    Tail(String),
+}
+
+/// Read a build's console output a line at a time, keeping the lines
+/// matching any of `patterns`, or if none do, the last lines.  Either way,
+/// no more than `lines` lines, so the whole output is never held at once.
+pub fn console_excerpt<R: BufRead>(
+    console: R,
+    patterns: &[Regex],
+    lines: usize,
+) -> io::Result<ConsoleExcerpt> {
+    let mut matching = Vec::new();
+    let mut tail = VecDeque::with_capacity(lines);
+    for line in console.split(b'\n') {
+        // Builds can print anything, not just UTF-8
+        let line = line?;
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches('\r');
+        if patterns.iter().any(|pattern| pattern.is_match(line)) {
+            matching.push(line.to_string());
+            // There's no need to read the rest
+            if matching.len() >= lines {
+                break;
+            }
+        } else if matching.is_empty() && lines > 0 {
+            if tail.len() == lines {
+                tail.pop_front();
+            }
+            tail.push_back(line.to_string());
+        }
+    }
+    if matching.is_empty() {
+        Ok(ConsoleExcerpt::Tail(Vec::from(tail).join("\n")))
+    } else {
+        Ok(ConsoleExcerpt::Matching(matching.join("\n")))
+    }
+}
+
+/// The results of the tests in one JUnit test suite.
+#[derive(Default, Debug, PartialEq)]
+pub struct SuiteReport {
+    pub name: String,
+    pub passed: u64,
+    pub failed: u64,
+    pub skipped: u64,
+    /// The names of the test cases that failed
+    pub failures: Vec<String>,
+}
+
+impl SuiteReport {
+    fn add(&mut self, other: &SuiteReport) {
+        self.passed += other.passed;
+        self.failed += other.failed;
+        self.skipped += other.skipped;
+        self.failures.extend(other.failures.iter().cloned());
+    }
+}
+
+/// The JUnit test results Jenkins recorded for a build.
+#[derive(Default, Debug, PartialEq)]
+pub struct TestReport {
+    /// All the suites together
+    pub total: SuiteReport,
+    pub suites: Vec<SuiteReport>,
+}
+
+impl TestReport {
+    /// Get a summary of a test report from the JSON Jenkins gives for it.
+    /// Reports for builds with several parts, like matrix builds, have a
+    /// report for each part, which are combined.
+    pub fn from_json(json: &Value) -> TestReport {
+        let mut report = TestReport::default();
+        for child in json["childReports"].as_array().into_iter().flatten() {
+            report.add_suites(&child["result"]);
+        }
+        report.add_suites(json);
+        report
+    }
+
+    fn add_suites(&mut self, json: &Value) {
+        for suite in json["suites"].as_array().into_iter().flatten() {
+            let mut summary = SuiteReport {
+                name: suite["name"].as_str().unwrap_or_default().to_string(),
+                ..Default::default()
+            };
+            for case in suite["cases"].as_array().into_iter().flatten() {
+                match case["status"].as_str().unwrap_or_default() {
+                    "PASSED" | "FIXED" => summary.passed += 1,
+                    "SKIPPED" => summary.skipped += 1,
+                    _ => {
+                        summary.failed += 1;
+                        let name = case["name"].as_str().unwrap_or_default();
+                        summary.failures.push(match case["className"].as_str() {
+                            Some(class) if !class.is_empty() => format!("{}.{}", class, name),
+                            _ => name.to_string(),
+                        });
+                    }
+                }
+            }
+            self.total.add(&summary);
+            // Suites can be split across reports
+            match self.suites.iter_mut().find(|s| s.name == summary.name) {
+                Some(existing) => existing.add(&summary),
+                None => self.suites.push(summary),
+            }
+        }
+    }
+}
+
 #[derive(Eq, PartialEq)]
 pub enum JenkinsBuildStatus {
     Running,
     Done,
+    TimedOut,
+}
+
+/// Why we couldn't get something from the Jenkins API.
+#[derive(Debug, Eq, PartialEq)]
+pub enum ApiError {
+    /// Jenkins doesn't have it, or no longer does
+    NotFound,
+    /// Jenkins couldn't be reached or didn't make sense, even after retrying
+    Failed(String),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ApiError::NotFound => write!(f, "Jenkins doesn't have it any more"),
+            ApiError::Failed(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// What's become of a build we queued.
+#[derive(Debug, Eq, PartialEq)]
+pub enum QueueItem {
+    Waiting,
+    Cancelled,
+    // The URL of the build it started
+    Started(String),
+    // Jenkins forgets items a while after they leave the queue, so we can't
+    // tell what happened to it
+    Lost,
+}
+
+impl QueueItem {
+    fn from_json(entry: &Value) -> QueueItem {
+        // Items that left the queue without starting have a null executable
+        match entry["executable"]["url"].as_str() {
+            Some(url) => QueueItem::Started(url.to_string()),
+            None if entry["cancelled"].as_bool() == Some(true) => QueueItem::Cancelled,
+            None => QueueItem::Waiting,
+        }
+    }
+}
+
+// Whether a deadline in seconds since the epoch has passed
+fn past(deadline: Option<u64>) -> bool {
+    deadline.map_or(false, |deadline| utils::timestamp() >= deadline)
 }
 
 impl JenkinsBackend {
+    pub fn new(
+        base_url: &str,
+        reqwest_client: Arc<Client>,
+        username: Option<String>,
+        token: Option<String>,
+    ) -> JenkinsBackend {
+        JenkinsBackend {
+            base_url: base_url.to_string(),
+            reqwest_client: reqwest_client,
+            username: username,
+            token: token,
+            crumb: Mutex::new(CrumbState::Unknown),
+        }
+    }
+
     fn headers(&self) -> Headers {
         let mut headers = Headers::new();
         if let Some(ref username) = self.username {
@@ -102,74 +313,178 @@ impl JenkinsBackend {
         self.reqwest_client.get(url).headers(self.headers()).send()
     }
 
-    fn post_url<U: IntoUrl>(&self, url: U) -> Result<Response, reqwest::Error> {
-        self.reqwest_client.post(url).headers(self.headers()).send()
+    // Ask Jenkins for a crumb, if it hands them out
+    fn fetch_crumb(&self) -> Result<CrumbState, reqwest::Error> {
+        let mut resp = self.get_url(&format!("{}/crumbIssuer/api/json", self.base_url))?;
+        if !resp.status().is_success() {
+            debug!("Jenkins didn't issue a crumb: {}", resp.status());
+            return Ok(CrumbState::NotNeeded);
+        }
+        let cookies = resp
+            .headers()
+            .get::<SetCookie>()
+            .map(|set_cookie| set_cookie.iter().filter_map(|c| parse_cookie(c)).collect())
+            .unwrap_or_default();
+        let json: Value = resp.json()?;
+        match (json["crumbRequestField"].as_str(), json["crumb"].as_str()) {
+            (Some(field), Some(value)) => Ok(CrumbState::Issued(Crumb {
+                field: field.to_string(),
+                value: value.to_string(),
+                cookies: cookies,
+            })),
+            _ => {
+                warn!("Couldn't understand crumb from Jenkins: {}", json);
+                Ok(CrumbState::NotNeeded)
+            }
+        }
+    }
+
+    // The crumb to send with POSTs, if Jenkins wants one.  It's fetched the
+    // first time and kept until `refresh` says it's been rejected.
+    fn crumb(&self, refresh: bool) -> Result<Option<Crumb>, reqwest::Error> {
+        let mut state = self.crumb.lock().unwrap();
+        if refresh {
+            *state = CrumbState::Unknown;
+        }
+        if let CrumbState::Unknown = *state {
+            *state = self.fetch_crumb()?;
+        }
+        Ok(match *state {
+            CrumbState::Issued(ref crumb) => Some(crumb.clone()),
+            _ => None,
+        })
+    }
+
+    fn post_with_crumb(&self, url: &str, refresh: bool) -> Result<Response, reqwest::Error> {
+        let mut headers = self.headers();
+        if let Some(crumb) = self.crumb(refresh)? {
+            headers.set_raw(crumb.field, crumb.value);
+            if !crumb.cookies.is_empty() {
+                let mut cookie = Cookie::new();
+                for (name, value) in crumb.cookies {
+                    cookie.append(name, value);
+                }
+                headers.set(cookie);
+            }
+        }
+        self.reqwest_client.post(url).headers(headers).send()
+    }
+
+    fn post_url(&self, url: &str) -> Result<Response, reqwest::Error> {
+        let resp = self.post_with_crumb(url, false)?;
+        if resp.status() == StatusCode::Forbidden {
+            // The crumb may have expired along with its session
+            debug!("Jenkins refused {}, trying again with a new crumb", url);
+            return self.post_with_crumb(url, true);
+        }
+        Ok(resp)
     }
 
-    fn get_api_json_object(&self, base_url: &str) -> Value {
-        // TODO: Don't panic on failure, fail more gracefully
+    fn get_api_json_object(&self, base_url: &str) -> Result<Value, ApiError> {
         let url = format!("{}api/json", base_url);
-        let mut result_str = String::new();
+        let mut retries = 0;
         loop {
-            let mut resp = match self.get_url(&url) {
-                Ok(r) => r,
-                Err(e) => {
-                    warn!("Couldn't hit Jenkins API: {}", e);
-                    sleep(Duration::from_millis(JENKINS_POLLING_INTERVAL));
-                    continue;
+            let err = match self.get_url(&url) {
+                Ok(ref resp) if resp.status() == StatusCode::NotFound => {
+                    return Err(ApiError::NotFound)
+                }
+                // Jenkins may be restarting, so give it a while
+                Ok(ref resp) if resp.status().is_server_error() => {
+                    format!("Jenkins returned {}", resp.status())
                 }
+                Ok(mut resp) => {
+                    let mut result_str = String::new();
+                    let result = if resp.status().is_success() {
+                        resp.read_to_string(&mut result_str)
+                            .map_err(|err| err.to_string())
+                            .and_then(|_| {
+                                serde_json::from_str(&result_str).map_err(|err| err.to_string())
+                            })
+                    } else {
+                        Err(format!("Jenkins returned {}", resp.status()))
+                    };
+                    return result
+                        .map_err(|err| ApiError::Failed(format!("Couldn't get {}: {}", url, err)));
+                }
+                Err(err) => err.to_string(),
             };
-
-            if resp.status().is_server_error() {
-                sleep(Duration::from_millis(JENKINS_POLLING_INTERVAL));
-                continue;
+            if retries >= JENKINS_API_RETRIES {
+                return Err(ApiError::Failed(format!("Couldn't get {}: {}", url, err)));
             }
-            resp.read_to_string(&mut result_str)
-                .unwrap_or_else(|err| panic!("Couldn't read from server: {}", err));
-            break;
+            warn!("Couldn't hit Jenkins API, retrying: {}", err);
+            retries += 1;
+            sleep(Duration::from_millis(JENKINS_POLLING_INTERVAL));
+        }
+    }
+
+    /// Find out whether a queued build has started yet.
+    pub fn get_queue_item(&self, build_queue_entry: &str) -> Result<QueueItem, ApiError> {
+        match self.get_api_json_object(build_queue_entry) {
+            Ok(entry) => Ok(QueueItem::from_json(&entry)),
+            Err(ApiError::NotFound) => Ok(QueueItem::Lost),
+            Err(err) => Err(err),
         }
-        serde_json::from_str(&result_str)
-            .unwrap_or_else(|err| panic!("Couldn't parse JSON from Jenkins: {}", err))
     }
 
-    pub fn get_build_url(&self, build_queue_entry: &str) -> Option<String> {
+    /// Wait for a queued build to leave the queue, and find out what became
+    /// of it.  If it's still `Waiting` by `deadline`, in seconds since the
+    /// epoch, we stop waiting.
+    pub fn wait_queue_item(
+        &self,
+        build_queue_entry: &str,
+        deadline: Option<u64>,
+    ) -> Result<QueueItem, ApiError> {
         loop {
-            let entry = self.get_api_json_object(build_queue_entry);
-            match entry.get("executable") {
-                Some(exec) => {
-                    return Some(
-                        exec
-                                          .as_object() // Option<BTreeMap>
-                                          .unwrap() // BTreeMap
-                                          .get("url") // Option<&str>
-                                          .unwrap() // &str ?
-                                          .as_str()
-                                          .unwrap()
-                                          .to_string(),
-                    );
+            match self.get_queue_item(build_queue_entry)? {
+                QueueItem::Waiting if !past(deadline) => {
+                    sleep(Duration::from_millis(JENKINS_POLLING_INTERVAL))
                 }
-                None => sleep(Duration::from_millis(JENKINS_POLLING_INTERVAL)),
+                item => return Ok(item),
             }
         }
     }
 
-    pub fn get_build_status(&self, build_url: &str) -> JenkinsBuildStatus {
-        if self.get_api_json_object(build_url)["building"]
+    // POST to a URL that makes Jenkins do something, and check it did
+    fn post_action(&self, url: &str) -> Result<(), String> {
+        let resp = self.post_url(url).map_err(|err| err.to_string())?;
+        // Jenkins redirects back to the page for the thing it acted on
+        if resp.status().is_success() || resp.status().is_redirection() {
+            Ok(())
+        } else {
+            Err(format!("Jenkins returned {} for {}", resp.status(), url))
+        }
+    }
+
+    /// Take a build out of the queue before it starts.
+    pub fn cancel_queue_item(&self, build_queue_entry: &str) -> Result<(), String> {
+        // Queue URLs look like https://jenkins/queue/item/123/
+        let id = build_queue_entry
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .unwrap_or("");
+        self.post_action(&format!("{}/queue/cancelItem?id={}", self.base_url, id))
+    }
+
+    /// Abort a running build.
+    pub fn stop_build(&self, build_url: &str) -> Result<(), String> {
+        self.post_action(&format!("{}/stop", build_url.trim_end_matches('/')))
+    }
+
+    pub fn get_build_status(&self, build_url: &str) -> Result<JenkinsBuildStatus, ApiError> {
+        if self.get_api_json_object(build_url)?["building"]
             .as_bool()
-            .unwrap()
+            .unwrap_or(false)
         {
-            JenkinsBuildStatus::Running
+            Ok(JenkinsBuildStatus::Running)
         } else {
-            JenkinsBuildStatus::Done
+            Ok(JenkinsBuildStatus::Done)
         }
     }
 
-    pub fn get_build_result(&self, build_url: &str) -> Option<TestState> {
-        match self.get_api_json_object(build_url)
-            .get("result")
-            .unwrap()
-            .as_str()
-        {
+    pub fn get_build_result(&self, build_url: &str) -> Result<Option<TestState>, ApiError> {
+        let build = self.get_api_json_object(build_url)?;
+        Ok(match build["result"].as_str() {
             None => None,
             Some(result) => match result {
                 // TODO: Improve this...
@@ -178,7 +493,7 @@ impl JenkinsBackend {
                 "UNSTABLE" => Some(TestState::Warning),
                 _ => Some(TestState::Pending),
             },
-        }
+        })
     }
 
     pub fn get_results_url(&self, build_url: &str, job: &BTreeMap<String, String>) -> String {
@@ -188,11 +503,292 @@ impl JenkinsBackend {
         }
     }
 
-    pub fn wait_build(&self, build_url: &str) -> JenkinsBuildStatus {
-        // TODO: Implement a timeout?
-        while self.get_build_status(build_url) != JenkinsBuildStatus::Done {
+    /// Get the JUnit test results of a build, if it recorded any.
+    pub fn get_test_report(&self, build_url: &str) -> Option<TestReport> {
+        // Only ask for what we use, since reports include the output of
+        // every test
+        let cases = "suites[name,cases[className,name,status]]";
+        let url = format!(
+            "{}/testReport/api/json?tree={},childReports[result[{}]]",
+            build_url.trim_end_matches('/'),
+            cases,
+            cases
+        );
+        let mut resp = match self.get_url(&url) {
+            Ok(resp) => resp,
+            Err(err) => {
+                warn!("Couldn't get test report from {}: {}", url, err);
+                return None;
+            }
+        };
+        // Builds that didn't record any results don't have a report
+        if !resp.status().is_success() {
+            return None;
+        }
+        match resp.json::<Value>() {
+            Ok(json) => Some(TestReport::from_json(&json)),
+            Err(err) => {
+                warn!("Couldn't parse test report from {}: {}", url, err);
+                None
+            }
+        }
+    }
+
+    /// Get the interesting part of a build's console output, as for
+    /// `console_excerpt`, reading it as it arrives since it can be huge.
+    pub fn get_console_excerpt(
+        &self,
+        build_url: &str,
+        patterns: &[Regex],
+        lines: usize,
+    ) -> Result<ConsoleExcerpt, String> {
+        let url = format!("{}/consoleText", build_url.trim_end_matches('/'));
+        let resp = self
+            .get_url(&url)
+            .and_then(Response::error_for_status)
+            .map_err(|err| err.to_string())?;
+        console_excerpt(BufReader::new(resp), patterns, lines).map_err(|err| err.to_string())
+    }
+
+    /// Wait for a build to finish, until no later than `deadline`, in seconds
+    /// since the epoch.
+    pub fn wait_build(
+        &self,
+        build_url: &str,
+        deadline: Option<u64>,
+    ) -> Result<JenkinsBuildStatus, ApiError> {
+        while self.get_build_status(build_url)? != JenkinsBuildStatus::Done {
+            if past(deadline) {
+                return Ok(JenkinsBuildStatus::TimedOut);
+            }
             sleep(Duration::from_millis(JENKINS_POLLING_INTERVAL));
         }
-        JenkinsBuildStatus::Done
+        Ok(JenkinsBuildStatus::Done)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use jenkins::*;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let len = stream.read(&mut buf).unwrap();
+            if len == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..len]);
+        }
+        String::from_utf8(request).unwrap()
+    }
+
+    // A Jenkins that only takes the second crumb it hands out, as if the
+    // first one's session had expired.  It answers `requests` requests, and
+    // returns the request line of each.
+    fn fake_jenkins(requests: usize) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let queue_url = format!("{}/queue/item/7/", url);
+        let server = thread::spawn(move || {
+            let mut crumbs = 0;
+            let mut seen = Vec::new();
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let request = read_request(&mut stream);
+                let (status, location, body) = if request.starts_with("GET /crumbIssuer/") {
+                    crumbs += 1;
+                    let body = format!(
+                        r#"{{"crumbRequestField": "Jenkins-Crumb", "crumb": "crumb{}"}}"#,
+                        crumbs
+                    );
+                    ("200 OK", "", body)
+                } else if request.to_lowercase().contains("jenkins-crumb: crumb2\r\n") {
+                    ("201 Created", queue_url.as_str(), String::new())
+                } else {
+                    ("403 Forbidden", "", String::new())
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+                    status,
+                    body.len()
+                )
+                .unwrap();
+                if !location.is_empty() {
+                    write!(stream, "Location: {}\r\n", location).unwrap();
+                }
+                write!(stream, "\r\n{}", body).unwrap();
+                seen.push(request.lines().next().unwrap_or_default().to_string());
+            }
+            seen
+        });
+        (url, server)
+    }
+
+    #[test]
+    fn expired_crumbs_are_refreshed() {
+        let (url, server) = fake_jenkins(4);
+        let jenkins = JenkinsBackend::new(&url, Arc::new(Client::new()), None, None);
+        assert_eq!(
+            jenkins.start_test("linux", vec![("BRANCH", "42_master")]),
+            Ok(format!("{}/queue/item/7/", url))
+        );
+        let crumb = "GET /crumbIssuer/api/json HTTP/1.1";
+        let build = "POST /job/linux/buildWithParameters?BRANCH=42_master HTTP/1.1";
+        assert_eq!(server.join().unwrap(), vec![crumb, build, crumb, build]);
+    }
+
+    // A Jenkins that answers each request in turn with a status and body
+    fn fake_api(responses: Vec<(&'static str, &'static str)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                read_request(&mut stream);
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn forgotten_queue_items_are_lost() {
+        let url = fake_api(vec![
+            ("404 Not Found", "<html><body>Not found</body></html>"),
+            ("200 OK", r#"{"building": false, "result": "SUCCESS"}"#),
+        ]);
+        let jenkins = JenkinsBackend::new(&url, Arc::new(Client::new()), None, None);
+        let queue_url = format!("{}/queue/item/7/", url);
+        assert_eq!(jenkins.get_queue_item(&queue_url), Ok(QueueItem::Lost));
+        let build_url = format!("{}/job/linux/3/", url);
+        assert_eq!(
+            jenkins.get_build_result(&build_url),
+            Ok(Some(TestState::Success))
+        );
+    }
+
+    #[test]
+    fn queue_items() {
+        let item = |json: &str| QueueItem::from_json(&serde_json::from_str(json).unwrap());
+        assert_eq!(
+            item(r#"{"id": 7, "why": "Waiting for next available executor"}"#),
+            QueueItem::Waiting
+        );
+        assert_eq!(
+            item(r#"{"id": 7, "cancelled": true, "executable": null}"#),
+            QueueItem::Cancelled
+        );
+        assert_eq!(
+            item(r#"{"executable": {"number": 3, "url": "https://jenkins/job/linux/3/"}}"#),
+            QueueItem::Started("https://jenkins/job/linux/3/".to_string())
+        );
+    }
+
+    #[test]
+    fn cookies_from_set_cookie() {
+        assert_eq!(
+            parse_cookie("JSESSIONID.3e1a2b7c=node0abc123.node0; Path=/; HttpOnly"),
+            Some((
+                "JSESSIONID.3e1a2b7c".to_string(),
+                "node0abc123.node0".to_string()
+            ))
+        );
+        assert_eq!(parse_cookie("=nameless"), None);
+        assert_eq!(parse_cookie("garbage"), None);
+    }
+
+    #[test]
+    fn test_reports() {
+        let json: Value = serde_json::from_str(
+            r#"{
+                "suites": [
+                    {
+                        "name": "net",
+                        "cases": [
+                            { "className": "net.tcp", "name": "connect", "status": "PASSED" },
+                            { "className": "net.tcp", "name": "reset", "status": "REGRESSION" },
+                            { "className": "", "name": "udp", "status": "SKIPPED" }
+                        ]
+                    }
+                ],
+                "childReports": [
+                    {
+                        "result": {
+                            "suites": [
+                                {
+                                    "name": "mm",
+                                    "cases": [
+                                        { "className": "mm", "name": "hugepages", "status": "FAILED" }
+                                    ]
+                                }
+                            ]
+                        }
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let report = TestReport::from_json(&json);
+        assert_eq!(report.total.passed, 1);
+        assert_eq!(report.total.failed, 2);
+        assert_eq!(report.total.skipped, 1);
+        assert_eq!(report.total.failures, vec!["mm.hugepages", "net.tcp.reset"]);
+        assert_eq!(report.suites.len(), 2);
+        assert_eq!(report.suites[1].name, "net");
+        assert_eq!(report.suites[1].failures, vec!["net.tcp.reset"]);
+    }
+
+    #[test]
+    fn console_excerpts() {
+        let console = "Started by user snowpatch\r\n\
+                       make -j8\n\
+                       foo.c:12:5: error: 'bar' undeclared\n\
+                       foo.c:20:1: warning: unused variable 'baz'\n\
+                       make: *** [foo.o] Error 1\n\
+                       Finished: FAILURE\n";
+        let patterns = vec![
+            Regex::new("error:").unwrap(),
+            Regex::new(r"\*\*\*").unwrap(),
+        ];
+        let excerpt = |patterns: &[Regex], lines| {
+            console_excerpt(console.as_bytes(), patterns, lines).unwrap()
+        };
+        assert_eq!(
+            excerpt(&patterns, 20),
+            ConsoleExcerpt::Matching(
+                "foo.c:12:5: error: 'bar' undeclared\nmake: *** [foo.o] Error 1".to_string()
+            )
+        );
+        assert_eq!(
+            excerpt(&patterns, 1),
+            ConsoleExcerpt::Matching("foo.c:12:5: error: 'bar' undeclared".to_string())
+        );
+        assert_eq!(
+            excerpt(&[], 2),
+            ConsoleExcerpt::Tail("make: *** [foo.o] Error 1\nFinished: FAILURE".to_string())
+        );
+        assert_eq!(
+            excerpt(&[], 20),
+            ConsoleExcerpt::Tail(console.replace("\r", "").trim_end().to_string())
+        );
+        // Output that isn't UTF-8 doesn't stop us finding the errors
+        let garbled = &b"\xff\xfe\nfoo.c:12:5: error: 'bar' undeclared\n"[..];
+        assert_eq!(
+            console_excerpt(garbled, &patterns, 20).unwrap(),
+            ConsoleExcerpt::Matching("foo.c:12:5: error: 'bar' undeclared".to_string())
+        );
     }
 }
@@ -21,7 +21,11 @@
 
 extern crate docopt;
 extern crate git2;
+extern crate rand;
+extern crate regex;
 extern crate reqwest;
+#[macro_use]
+extern crate rusqlite;
 extern crate tempdir;
 extern crate url;
 #[macro_use]
@@ -33,7 +37,7 @@ extern crate serde_derive;
 extern crate serde_json;
 extern crate toml;
 
-use git2::{BranchType, PushOptions, RemoteCallbacks};
+use git2::{FetchOptions, PushOptions, Remote};
 
 use reqwest::{Client, Proxy};
 
@@ -44,24 +48,44 @@ use url::Url;
 use env_logger::Builder;
 use log::LevelFilter;
 
+use std::collections::BTreeMap;
 use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::string::String;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, PoisonError};
 use std::thread;
 use std::time::Duration;
 
 mod patchwork;
-use patchwork::{PatchworkServer, TestResult, TestState};
+use patchwork::{
+    Event, Patch, PatchworkError, PatchworkServer, RetryPolicy, TestResult, TestState,
+};
 
 mod jenkins;
-use jenkins::{CIBackend, JenkinsBackend};
+use jenkins::{
+    CIBackend, ConsoleExcerpt, JenkinsBackend, JenkinsBuildStatus, QueueItem, SuiteReport,
+    TestReport,
+};
 
 mod settings;
-use settings::{Config, Project};
+use settings::{Branch, Config, Job, Project};
+
+mod scheduler;
+use scheduler::Scheduler;
+
+mod state;
+use state::{RunKey, StateStore, TestRecord};
 
 mod git;
+use git::RefNames;
+
+mod mbox;
+
+mod outbox;
+use outbox::Outbox;
+
+mod report;
 
 mod utils;
 
@@ -71,6 +95,10 @@ Usage:
   snowpatch <config-file> --project <name> --mbox <mbox>
   snowpatch <config-file> --patch <id>
   snowpatch <config-file> --series <id>
+  snowpatch <config-file> outbox list
+  snowpatch <config-file> outbox flush
+  snowpatch <config-file> outbox drop (--all | <result-id>...)
+  snowpatch <config-file> gc [--dry-run]
   snowpatch -v | --version
   snowpatch -h | --help
 
@@ -82,6 +110,8 @@ Options:
   --patch <id>              Run tests on the given Patchwork patch.
   --series <id>             Run tests on the given Patchwork series.
   --mbox <mbox>             Run tests on the given mbox file. Requires --project
+  --all                     Drop every result waiting in the outbox.
+  --dry-run                 List the refs gc would delete without deleting them.
   -v, --version             Output version information.
   -h, --help                Output this help text.
 ";
@@ -94,193 +124,1211 @@ struct Args {
     flag_series: u32,
     flag_mbox: String,
     flag_project: String,
+    flag_all: bool,
+    flag_dry_run: bool,
+    cmd_outbox: bool,
+    cmd_list: bool,
+    cmd_flush: bool,
+    cmd_drop: bool,
+    cmd_gc: bool,
+    arg_result_id: Vec<u64>,
 }
 
+#[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
 fn run_tests(
-    settings: &Config,
-    client: Arc<Client>,
+    jenkins: Arc<JenkinsBackend>,
+    store: Arc<StateStore>,
+    key: RunKey,
     project: &Project,
     tag: &str,
     branch_name: &str,
+    base: &str,
     hefty_tests: bool,
 ) -> Vec<TestResult> {
     let mut results: Vec<TestResult> = Vec::new();
-    let jenkins = JenkinsBackend {
-        base_url: settings.jenkins.url.clone(),
-        reqwest_client: client,
-        username: settings.jenkins.username.clone(),
-        token: settings.jenkins.token.clone(),
-    };
     let project = project.clone();
     for job in &project.jobs {
         if !hefty_tests && job.hefty {
             debug!("Skipping hefty test {}", job.title);
             continue;
         }
-        let mut jenkins_params = Vec::<(&str, &str)>::new();
-        for (param_name, param_value) in job.parameters.iter() {
-            // TODO(ajd): do this more neatly
-            debug!("Param name {}, value {}", &param_name, &param_value);
-            jenkins_params.push((param_name, param_value));
+        results.append(&mut start_build(
+            &jenkins,
+            &store,
+            &key,
+            &project,
+            job,
+            tag,
+            branch_name,
+            Some(base),
+        ));
+    }
+    results
+}
+
+// The jobs run_tests will run on a branch
+fn job_titles(project: &Project, hefty_tests: bool) -> Vec<&str> {
+    project
+        .jobs
+        .iter()
+        .filter(|job| hefty_tests || !job.hefty)
+        .map(|job| job.title.as_str())
+        .collect()
+}
+
+/// Queue a Jenkins build of a job on the branch we pushed as `tag`, and wait
+/// for it to finish.
+#[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+fn start_build(
+    jenkins: &JenkinsBackend,
+    store: &StateStore,
+    key: &RunKey,
+    project: &Project,
+    job: &Job,
+    tag: &str,
+    branch_name: &str,
+    base: Option<&str>,
+) -> Vec<TestResult> {
+    let mut jenkins_params = Vec::<(&str, &str)>::new();
+    for (param_name, param_value) in job.parameters.iter() {
+        // TODO(ajd): do this more neatly
+        debug!("Param name {}, value {}", &param_name, &param_value);
+        jenkins_params.push((param_name, param_value));
+    }
+    jenkins_params.push((&job.remote, &project.remote_uri));
+    jenkins_params.push((&job.branch, tag));
+
+    info!("Starting job: {}", &job.title);
+    let res = jenkins
+        .start_test(&job.job, jenkins_params)
+        .unwrap_or_else(|err| panic!("Starting Jenkins test failed: {}", err));
+    debug!("{:?}", &res);
+    let queued = utils::timestamp();
+    store.record_build_queued(key, branch_name, &job.title, &res, queued);
+    wait_for_build(
+        jenkins,
+        store,
+        key,
+        job,
+        branch_name,
+        base,
+        &res,
+        queued,
+        None,
+    )
+}
+
+/// Wait for a Jenkins build we've queued to finish, and get its result,
+/// followed by a result for each of the job's `test_suites`.  If we already
+/// know the build's URL and when it started, we don't need to find it from
+/// the queue.  Timeouts count from the times given, which are in seconds
+/// since the epoch, so they don't start again if snowpatch restarts.
+#[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+fn wait_for_build(
+    jenkins: &JenkinsBackend,
+    store: &StateStore,
+    key: &RunKey,
+    job: &Job,
+    branch_name: &str,
+    base: Option<&str>,
+    queue_url: &str,
+    queued: u64,
+    started: Option<(String, u64)>,
+) -> Vec<TestResult> {
+    // We can't tell how the build went, but that's still worth reporting
+    let lost = |reason: &str, target_url: Option<String>| {
+        warn!(
+            "Lost track of Jenkins job for {}/{}: {}",
+            branch_name, job.title, reason
+        );
+        store.record_build_finished(key, branch_name, &job.title);
+        vec![lost_result(job, branch_name, base, reason, target_url)]
+    };
+    let (build_url_real, started) = match started {
+        Some(started) => started,
+        None => {
+            let deadline = job.queue_timeout.map(|timeout| queued + timeout);
+            let build_url_real = match jenkins.wait_queue_item(queue_url, deadline) {
+                Ok(QueueItem::Started(url)) => url,
+                Ok(QueueItem::Lost) => {
+                    return lost("Jenkins no longer has it in its queue", None);
+                }
+                Err(err) => return lost(&err.to_string(), None),
+                Ok(QueueItem::Waiting) | Ok(QueueItem::Cancelled) => {
+                    warn!(
+                        "Jenkins job for {}/{} didn't start in time, cancelling it",
+                        branch_name, job.title
+                    );
+                    jenkins.cancel_queue_item(queue_url).unwrap_or_else(|err| {
+                        warn!("Couldn't cancel queued build {}: {}", queue_url, err)
+                    });
+                    // It may have started just before we cancelled it
+                    let target_url = match jenkins.get_queue_item(queue_url) {
+                        Ok(QueueItem::Started(url)) => {
+                            jenkins.stop_build(&url).unwrap_or_else(|err| {
+                                warn!("Couldn't stop build {}: {}", url, err)
+                            });
+                            Some(jenkins.get_results_url(&url, &job.parameters))
+                        }
+                        _ => None,
+                    };
+                    store.record_build_finished(key, branch_name, &job.title);
+                    let reason = format!(
+                        "waiting {} seconds for an executor",
+                        job.queue_timeout.unwrap_or(0)
+                    );
+                    return vec![timed_out_result(
+                        job,
+                        branch_name,
+                        base,
+                        &reason,
+                        target_url,
+                    )];
+                }
+            };
+            debug!("Build URL: {}", build_url_real);
+            let started = utils::timestamp();
+            store.record_build_started(key, branch_name, &job.title, &build_url_real, started);
+            (build_url_real, started)
         }
-        jenkins_params.push((&job.remote, &project.remote_uri));
-        jenkins_params.push((&job.branch, tag));
-
-        info!("Starting job: {}", &job.title);
-        let res = jenkins
-            .start_test(&job.job, jenkins_params)
-            .unwrap_or_else(|err| panic!("Starting Jenkins test failed: {}", err));
-        debug!("{:?}", &res);
-        let build_url_real;
-        loop {
-            let build_url = jenkins.get_build_url(&res);
-            if let Some(url) = build_url {
-                build_url_real = url;
-                break;
+    };
+    let deadline = job.build_timeout.map(|timeout| started + timeout);
+    let target_url = jenkins.get_results_url(&build_url_real, &job.parameters);
+    let status = match jenkins.wait_build(&build_url_real, deadline) {
+        Ok(status) => status,
+        Err(err) => return lost(&err.to_string(), Some(target_url)),
+    };
+    if status == JenkinsBuildStatus::TimedOut {
+        warn!(
+            "Jenkins job for {}/{} took too long, stopping it",
+            branch_name, job.title
+        );
+        jenkins
+            .stop_build(&build_url_real)
+            .unwrap_or_else(|err| warn!("Couldn't stop build {}: {}", build_url_real, err));
+        store.record_build_finished(key, branch_name, &job.title);
+        let reason = format!(
+            "running for {} seconds, and was stopped",
+            job.build_timeout.unwrap_or(0)
+        );
+        return vec![timed_out_result(
+            job,
+            branch_name,
+            base,
+            &reason,
+            Some(target_url),
+        )];
+    }
+    let mut test_result = match jenkins.get_build_result(&build_url_real) {
+        Ok(Some(result)) => result,
+        Ok(None) => return lost("Jenkins didn't give a result", Some(target_url)),
+        Err(err) => return lost(&err.to_string(), Some(target_url)),
+    };
+    store.record_build_finished(key, branch_name, &job.title);
+    info!("Jenkins job for {}/{} complete.", branch_name, job.title);
+    let mut description = format!(
+        "Test {} on branch {}{}",
+        job.title,
+        branch_name,
+        describe_base(base)
+    );
+    let report = jenkins.get_test_report(&build_url_real);
+    if let Some(ref report) = report {
+        description.push_str("\n\n");
+        description.push_str(&utils::excerpt(
+            &describe_tests(&report.total),
+            EXCERPT_SIZE,
+        ));
+    }
+    // Show why it failed, so there's no need to go looking
+    if (test_result == TestState::Fail || test_result == TestState::Warning)
+        && job.console_lines > 0
+    {
+        let patterns = &job.console_patterns;
+        match jenkins.get_console_excerpt(&build_url_real, patterns, job.console_lines) {
+            Ok(excerpt) => {
+                // The end of the output is what matters if nothing matched
+                let excerpt = match excerpt {
+                    ConsoleExcerpt::Matching(ref lines) => utils::excerpt(lines, EXCERPT_SIZE),
+                    ConsoleExcerpt::Tail(ref lines) => utils::excerpt_tail(lines, EXCERPT_SIZE),
+                };
+                if !excerpt.is_empty() {
+                    description.push_str("\n\n");
+                    description.push_str(&excerpt);
+                }
             }
+            Err(err) => warn!("Couldn't get console output of {}: {}", build_url_real, err),
         }
-        debug!("Build URL: {}", build_url_real);
-        jenkins.wait_build(&build_url_real);
-        let mut test_result = jenkins.get_build_result(&build_url_real).unwrap();
-        info!("Jenkins job for {}/{} complete.", branch_name, job.title);
-        if test_result == TestState::Fail && job.warn_on_fail {
-            test_result = TestState::Warning;
-        }
-        results.push(TestResult {
-            description: Some(
-                format!("Test {} on branch {}", job.title, branch_name.to_string()).to_string(),
-            ),
-            state: test_result,
-            context: Some(format!("{}", job.title.replace("/", "_")).to_string()),
-            target_url: Some(jenkins.get_results_url(&build_url_real, &job.parameters)),
-        });
+    }
+    if test_result == TestState::Fail && job.warn_on_fail {
+        test_result = TestState::Warning;
+    }
+    let mut results = vec![TestResult {
+        description: Some(description),
+        state: test_result,
+        context: Some(format!("{}", job.title.replace("/", "_")).to_string()),
+        target_url: Some(target_url),
+    }];
+    for suite in &job.test_suites {
+        results.push(suite_result(
+            job,
+            branch_name,
+            base,
+            &build_url_real,
+            report.as_ref(),
+            suite,
+        ));
     }
     results
 }
 
+// The most failing tests to name in a result
+static MAX_FAILURES: usize = 10;
+
+// A summary of test results, naming the first few tests that failed
+fn describe_tests(suite: &SuiteReport) -> String {
+    let mut summary = format!(
+        "{} passed, {} failed, {} skipped",
+        suite.passed, suite.failed, suite.skipped
+    );
+    for failure in suite.failures.iter().take(MAX_FAILURES) {
+        summary.push_str(&format!("\nFailed: {}", failure));
+    }
+    if suite.failures.len() > MAX_FAILURES {
+        summary.push_str(&format!(
+            "\n...and {} more",
+            suite.failures.len() - MAX_FAILURES
+        ));
+    }
+    summary
+}
+
+// The result for one test suite of a build, from the build's test report
+fn suite_result(
+    job: &Job,
+    branch_name: &str,
+    base: Option<&str>,
+    build_url: &str,
+    report: Option<&TestReport>,
+    name: &str,
+) -> TestResult {
+    let suite = report.and_then(|report| report.suites.iter().find(|suite| suite.name == name));
+    let (state, summary) = match suite {
+        Some(suite) if suite.failed > 0 && job.warn_on_fail => {
+            (TestState::Warning, describe_tests(suite))
+        }
+        Some(suite) if suite.failed > 0 => (TestState::Fail, describe_tests(suite)),
+        Some(suite) => (TestState::Success, describe_tests(suite)),
+        None => (TestState::Warning, "No results for this suite".to_string()),
+    };
+    TestResult {
+        description: Some(format!(
+            "Suite {} of test {} on branch {}{}\n\n{}",
+            name,
+            job.title,
+            branch_name,
+            describe_base(base),
+            utils::excerpt(&summary, EXCERPT_SIZE)
+        )),
+        state: state,
+        context: Some(format!("{}_{}", job.title, name).replace("/", "_")),
+        target_url: Some(format!("{}/testReport/", build_url.trim_end_matches('/'))),
+    }
+}
+
+// The result of a build that took too long, saying what it was doing
+fn timed_out_result(
+    job: &Job,
+    branch_name: &str,
+    base: Option<&str>,
+    reason: &str,
+    target_url: Option<String>,
+) -> TestResult {
+    TestResult {
+        description: Some(format!(
+            "Test {} on branch {}{} timed out {}",
+            job.title,
+            branch_name,
+            describe_base(base),
+            reason
+        )),
+        state: if job.warn_on_fail {
+            TestState::Warning
+        } else {
+            TestState::Fail
+        },
+        context: Some(job.title.replace("/", "_")),
+        target_url: target_url,
+    }
+}
+
+// The result of a job that was never queued, because the branch it was to
+// test couldn't be pushed or had been deleted
+fn lost_result(
+    job: &Job,
+    branch_name: &str,
+    base: Option<&str>,
+    reason: &str,
+    target_url: Option<String>,
+) -> TestResult {
+    TestResult {
+        description: Some(format!(
+            "Test {} on branch {}{} has no result, as we lost track of it: {}",
+            job.title,
+            branch_name,
+            describe_base(base),
+            reason
+        )),
+        state: TestState::Warning,
+        context: Some(job.title.replace("/", "_")),
+        target_url: target_url,
+    }
+}
+
+fn not_run_result(job: &Job, branch_name: &str, base: Option<&str>) -> TestResult {
+    TestResult {
+        description: Some(format!(
+            "Test {} on branch {}{} wasn't run, as the branch was no longer there to test",
+            job.title,
+            branch_name,
+            describe_base(base)
+        )),
+        state: TestState::Warning,
+        context: Some(job.title.replace("/", "_")),
+        ..Default::default()
+    }
+}
+
+// What we call the commit patches say they're based on when we apply them
+// there, in place of a branch name
+static STATED_BASE: &'static str = "base-commit";
+
+// Which commit the patches were applied to, if we know.  Runs recorded
+// before we kept track of it don't say.
+fn describe_base(base: Option<&str>) -> String {
+    base.map(|base| format!(" at {}", base)).unwrap_or_default()
+}
+
+// How much of a report or log goes in a result's description
+static EXCERPT_SIZE: usize = 1000;
+
+// The result of applying the patches to a branch, with why they didn't
+// apply if they didn't
+fn apply_result(branch_name: &str, base: Option<&str>, applied: Result<(), &str>) -> TestResult {
+    let (state, message, details) = match applied {
+        Ok(()) => (TestState::Success, "Successfully applied", ""),
+        Err(err) => (TestState::Warning, "Patch failed to apply", err),
+    };
+    let mut description = format!(
+        "{}/apply_patch\n\n{}{}",
+        branch_name,
+        message,
+        describe_base(base)
+    );
+    if !details.is_empty() {
+        description.push_str(&format!("\n\n{}", details));
+    }
+    TestResult {
+        state: state,
+        description: Some(description),
+        context: Some("apply_patch".to_string()),
+        ..Default::default()
+    }
+}
+
+// Where the full report of why patches didn't apply can be found, if it was
+// saved somewhere we've been told is served
+fn report_url(settings: &Config, name: Option<&String>) -> Option<String> {
+    let base = settings.state.as_ref()?.report_url.as_ref()?;
+    name.map(|name| format!("{}/{}", base.trim_end_matches('/'), name))
+}
+
+// Reported when the patches didn't apply to any of the project's branches
+fn apply_failed_result() -> TestResult {
+    TestResult {
+        state: TestState::Fail,
+        description: Some("Failed to apply to any branch".to_string()),
+        context: Some("apply_patch".to_string()),
+        ..Default::default()
+    }
+}
+
+// Reported when we couldn't fetch a branch to apply the patches to, so they
+// weren't tried on it
+fn fetch_failed_result(branch_name: &str, err: &git2::Error) -> TestResult {
+    TestResult {
+        state: TestState::Warning,
+        description: Some(format!(
+            "{}/fetch\n\nCouldn't update base branch {}: {}",
+            branch_name, branch_name, err
+        )),
+        context: Some("fetch".to_string()),
+        ..Default::default()
+    }
+}
+
+// Reported when the patches applied to a branch but we couldn't push it, so
+// it couldn't be tested
+fn push_failed_result(branch_name: &str, tag: &str, err: &git2::Error) -> TestResult {
+    TestResult {
+        state: TestState::Warning,
+        description: Some(format!(
+            "{}/push\n\nCouldn't push {} for testing: {}",
+            branch_name, tag, err
+        )),
+        context: Some("push".to_string()),
+        ..Default::default()
+    }
+}
+
+// Results for patches other than the one being tested, along with the
+// checks URL of the patch each one is for
+type PatchResults = Vec<(String, TestResult)>;
+
+// Delete a ref we pushed for testing.  If we can't, it's left for
+// collect_garbage to try again later.
+fn delete_ref(
+    store: &StateStore,
+    key: &RunKey,
+    remote: &mut Remote,
+    refname: &str,
+    opts: &mut PushOptions,
+) {
+    match git::delete_remote_ref(remote, refname, opts) {
+        Ok(()) => store.record_ref_deleted(key, refname),
+        Err(err) => warn!("Couldn't delete remote branch {}: {}", refname, err),
+    }
+}
+
+/// Delete the refs we've pushed for testing that aren't needed any more,
+/// including any left behind because snowpatch stopped or a test failed part
+/// way through.  With `dry_run`, just list them.
+fn collect_garbage(settings: &Config, store: &StateStore, dry_run: bool) {
+    let ttl = settings
+        .state
+        .as_ref()
+        .and_then(|state| state.ref_ttl)
+        .unwrap_or(86400);
+    for stale in store.stale_refs(ttl, utils::timestamp()) {
+        let project = match stale
+            .project
+            .as_ref()
+            .and_then(|name| settings.projects.get(name))
+        {
+            Some(project) => project,
+            None => {
+                warn!(
+                    "Can't delete {} for {}, its project is no longer configured",
+                    stale.refname, stale.key
+                );
+                continue;
+            }
+        };
+        if dry_run {
+            println!("{}\t{}\t{}", stale.key, project.remote_name, stale.refname);
+            continue;
+        }
+
+        let repo = match project.get_repo() {
+            Ok(repo) => repo,
+            Err(err) => {
+                warn!("Can't delete {}: {}", stale.refname, err);
+                continue;
+            }
+        };
+        let mut remote = match repo.find_remote(&project.remote_name) {
+            Ok(remote) => remote,
+            Err(err) => {
+                warn!("Can't delete {}: {}", stale.refname, err);
+                continue;
+            }
+        };
+        let mut push_opts = PushOptions::new();
+        push_opts.remote_callbacks(git::remote_callbacks(
+            &settings.git,
+            project.credentials.as_ref(),
+        ));
+        info!("Deleting {} for {}", stale.refname, stale.key);
+        delete_ref(
+            store,
+            &stale.key,
+            &mut remote,
+            &stale.refname,
+            &mut push_opts,
+        );
+    }
+}
+
+/// Apply the patches in an mbox to each of the project's branches, and test
+/// them.
+///
+/// To test a series a patch at a time as well, `bisect` holds the checks URL
+/// of each patch in the mbox, in order.  Each patch before the last is then
+/// tested on its own with the project's `bisect` jobs, and the results come
+/// back with the checks URL of the patch they're for.
+///
+/// `stated_base` is the commit the series says it's based on, if Patchwork
+/// or its cover letter says, which is tried before the project's branches.
+#[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
 fn test_patch(
     settings: &Config,
-    client: &Arc<Client>,
+    jenkins: &Arc<JenkinsBackend>,
+    store: &Arc<StateStore>,
+    git_lock: &Mutex<()>,
+    key: &RunKey,
     project: &Project,
     path: &Path,
+    refs: &RefNames,
     hefty_tests: bool,
-) -> Vec<TestResult> {
+    bisect: &[String],
+    stated_base: Option<&str>,
+) -> (Vec<TestResult>, PatchResults) {
     let repo = project.get_repo().unwrap();
     let mut results: Vec<TestResult> = Vec::new();
+    let mut patch_results = Vec::new();
     if !path.is_file() {
-        return results;
+        return (results, patch_results);
     }
-    let tag = utils::sanitise_path(path.file_name().unwrap().to_str().unwrap().to_string());
+    let name = utils::sanitise_path(path.file_name().unwrap().to_str().unwrap().to_string());
+    let ref_template = project
+        .ref_template
+        .as_ref()
+        .map_or(git::DEFAULT_REF_TEMPLATE, String::as_str);
     let mut remote = repo.find_remote(&project.remote_name).unwrap();
 
-    let mut push_callbacks = RemoteCallbacks::new();
-    push_callbacks.credentials(|_, _, _| git::cred_from_settings(&settings.git));
+    let credentials = project.credentials.as_ref();
 
     let mut push_opts = PushOptions::new();
-    push_opts.remote_callbacks(push_callbacks);
+    push_opts.remote_callbacks(git::remote_callbacks(&settings.git, credentials));
 
-    let mut successfully_applied = false;
-    for branch_name in project.branches.clone() {
-        let tag = format!("{}_{}", tag, branch_name);
-        info!("Configuring local branch for {}.", tag);
-        debug!("Switching to base branch {}...", branch_name);
-        git::checkout_branch(&repo, &branch_name);
-
-        // Make sure we're up to date
-        git::pull(&repo).unwrap();
-
-        debug!("Creating a new branch...");
-        let commit = git::get_latest_commit(&repo);
-        let mut branch = repo.branch(&tag, &commit, true).unwrap();
-        debug!("Switching to branch...");
-        repo.set_head(branch.get().name().unwrap())
-            .unwrap_or_else(|err| panic!("Couldn't set HEAD: {}", err));
-        repo.checkout_head(None)
-            .unwrap_or_else(|err| panic!("Couldn't checkout HEAD: {}", err));
-        debug!(
-            "Repo is now at head {}",
-            repo.head().unwrap().name().unwrap()
-        );
+    let mut fetch_opts = FetchOptions::new();
+    fetch_opts.remote_callbacks(git::remote_callbacks(&settings.git, credentials));
 
-        let output = git::apply_patch(&repo, path);
+    if project.clone_url.is_some() {
+        let _git = git_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        git::update_mirror(&repo).unwrap_or_else(|err| {
+            warn!("Couldn't update mirror, testing with what we have: {}", err)
+        });
+    }
 
-        if output.is_ok() {
-            git::push_to_remote(&mut remote, &branch, false, &mut push_opts).unwrap();
-        }
+    let mut bisect_project = project.clone();
+    bisect_project.jobs.retain(|job| job.bisect);
 
-        git::checkout_branch(&repo, &branch_name);
-        // we need to find the branch again since its head has moved
-        branch = repo.find_branch(&tag, BranchType::Local).unwrap();
-        branch.delete().unwrap();
-        debug!("Repo is back to {}", repo.head().unwrap().name().unwrap());
-
-        match output {
-            Ok(_) => {
-                successfully_applied = true;
-                results.push(TestResult {
-                    state: TestState::Success,
-                    description: Some(
-                        format!(
-                            "{}/{}\n\n{}",
-                            branch_name.to_string(),
-                            "apply_patch".to_string(),
-                            "Successfully applied".to_string()
-                        ).to_string(),
-                    ),
-                    context: Some("apply_patch".to_string()),
-                    ..Default::default()
-                });
+    // The tests for each branch run alongside each other
+    let spawn_tests = |project: Project, tag: &str, branch_name: &str, base: &str, hefty_tests| {
+        let jenkins = jenkins.clone();
+        let store = store.clone();
+        let key = key.clone();
+        let tag = tag.to_string();
+        let branch_name = branch_name.to_string();
+        let base = base.to_string();
+        thread::Builder::new()
+            .name(tag.clone())
+            .spawn(move || {
+                run_tests(
+                    jenkins,
+                    store,
+                    key,
+                    &project,
+                    &tag,
+                    &branch_name,
+                    &base,
+                    hefty_tests,
+                )
+            })
+            .unwrap()
+    };
+
+    let mut successfully_applied = false;
+    // Whether there was a branch we couldn't even try
+    let mut fetch_failed = false;
+    // How applying to each branch went, and the tests running on it if it
+    // applied
+    let mut branches = Vec::new();
+    // The tests running on each patch of a series, if testing a patch at a
+    // time
+    let mut patch_tests = Vec::new();
+    // If the patches say which commit they're based on and we have it, try
+    // that before the project's branches.  None stands for that commit.
+    let stated_base = git::stated_base_commit(&repo, path, stated_base);
+    let mut bases: Vec<Option<Branch>> = project.branches.iter().cloned().map(Some).collect();
+    if stated_base.is_some() {
+        bases.insert(0, None);
+    }
+    for branch in bases {
+        let (branch_name, base_commit) = match branch {
+            Some(branch) => {
+                // Only one test can fetch into the repository at a time
+                let _git = git_lock.lock().unwrap_or_else(PoisonError::into_inner);
+                match git::base_commit(&repo, &branch, &mut fetch_opts) {
+                    Ok(commit) => (branch.name, commit),
+                    // Carry on with the other branches, as this one may be
+                    // back by the next test
+                    Err(err) => {
+                        error!("Couldn't update base branch {}: {}", branch.name, err);
+                        fetch_failed = true;
+                        branches.push((fetch_failed_result(&branch.name, &err), None));
+                        continue;
+                    }
+                }
             }
-            Err(_) => {
+            None => (STATED_BASE.to_string(), stated_base.unwrap()),
+        };
+        let tag = refs.expand(ref_template, &name, &branch_name);
+        let base = base_commit.to_string();
+        info!("Applying patches for {} at {}.", tag, base);
+        let commits = match git::apply_mbox(&repo, base_commit, path) {
+            Ok(commits) => commits,
+            Err(err) => {
+                let report = err.report();
+                info!("Patches for {} failed to apply:\n{}", tag, report);
+                let excerpt = utils::excerpt(&report, EXCERPT_SIZE);
+                store.record_branch(key, &branch_name, &base, Err(&excerpt), &[]);
+                let report_name = store.save_report(key, &branch_name, &report);
                 // It didn't apply.  No need to bother testing.
-                results.push(TestResult {
-                    state: TestState::Warning,
-                    description: Some(
-                        format!(
-                            "{}/{}\n\n{}",
-                            branch_name.to_string(),
-                            "apply_patch".to_string(),
-                            "Patch failed to apply".to_string()
-                        ).to_string(),
-                    ),
-                    context: Some("apply_patch".to_string()),
-                    ..Default::default()
-                });
+                let mut result = apply_result(&branch_name, Some(&base), Err(&excerpt));
+                result.target_url = report_url(settings, report_name.as_ref());
+                branches.push((result, None));
                 continue;
             }
-        }
+        };
+        successfully_applied = true;
+        let head = *commits.last().unwrap();
 
-        let settings = settings.clone();
-        let project = project.clone();
-        let client = client.clone();
-        let test_all_branches = project.test_all_branches.unwrap_or(true);
+        // Push the patched commit for testing, recording it and the jobs we're
+        // going to run on it first so it can be cleaned up, or tested, even if
+        // we don't get any further
+        let jobs = job_titles(project, hefty_tests);
+        store.record_branch(key, &branch_name, &base, Ok(&tag), &jobs);
+        let applied = apply_result(&branch_name, Some(&base), Ok(()));
+        if let Err(err) = git::push_to_remote(&repo, &mut remote, head, &tag, &mut push_opts) {
+            error!("Couldn't push {} for testing: {}", tag, err);
+            store.record_ref_deleted(key, &tag);
+            branches.push((applied, None));
+            branches.push((push_failed_result(&branch_name, &tag, &err), None));
+            continue;
+        }
 
-        // We've set up a remote branch, time to kick off tests
-        let test = thread::Builder::new()
-            .name(tag.to_string())
-            .spawn(move || run_tests(&settings, client, &project, &tag, &branch_name, hefty_tests))
-            .unwrap();
-        results.append(&mut test.join().unwrap());
+        // Each patch before the last got a commit of its own, which we push
+        // too if we're testing a patch at a time
+        let mut patch_branches = Vec::new();
+        if bisect.len() > 1 && !bisect_project.jobs.is_empty() {
+            // We can only tell which commit is which patch if every patch in
+            // the series made one
+            let patch_commits = if commits.len() == bisect.len() {
+                &commits[..commits.len() - 1]
+            } else {
+                warn!(
+                    "Not testing {} a patch at a time: {} of {} patches made commits",
+                    tag,
+                    commits.len(),
+                    bisect.len()
+                );
+                &[]
+            };
+            for (i, &commit) in patch_commits.iter().enumerate() {
+                let patch_tag = format!("{}_{}", tag, i + 1);
+                let patch_branch_name =
+                    format!("{} (patch {}/{})", branch_name, i + 1, bisect.len());
+                store.record_patch_branch(
+                    key,
+                    &patch_branch_name,
+                    &base,
+                    &patch_tag,
+                    &bisect[i],
+                    &job_titles(&bisect_project, true),
+                );
+                match git::push_to_remote(&repo, &mut remote, commit, &patch_tag, &mut push_opts) {
+                    Ok(()) => {
+                        patch_branches.push((patch_tag, patch_branch_name, bisect[i].clone()))
+                    }
+                    Err(err) => {
+                        error!("Couldn't push {} for testing: {}", patch_tag, err);
+                        store.record_ref_deleted(key, &patch_tag);
+                        let result = push_failed_result(&patch_branch_name, &patch_tag, &err);
+                        patch_results.push((bisect[i].clone(), result));
+                    }
+                }
+            }
+        }
 
-        // Delete the remote branch now it's not needed any more
-        git::push_to_remote(&mut remote, &branch, true, &mut push_opts).unwrap();
+        // We've set up the remote branches, time to kick off tests.
+        let test = spawn_tests(project.clone(), &tag, &branch_name, &base, hefty_tests);
+        branches.push((applied, Some((tag, test))));
+        for (patch_tag, patch_branch_name, checks_url) in patch_branches {
+            let test = spawn_tests(
+                bisect_project.clone(),
+                &patch_tag,
+                &patch_branch_name,
+                &base,
+                true,
+            );
+            patch_tests.push((checks_url, patch_tag, test));
+        }
 
-        if !test_all_branches {
+        if !project.test_all_branches.unwrap_or(true) {
             break;
         }
     }
 
-    if !successfully_applied {
-        results.push(TestResult {
-            state: TestState::Fail,
-            description: Some("Failed to apply to any branch".to_string()),
-            context: Some("apply_patch".to_string()),
-            ..Default::default()
+    for (applied, test) in branches {
+        results.push(applied);
+        if let Some((remote_branch, test)) = test {
+            results.append(&mut test.join().unwrap());
+
+            // Delete the remote branch now it's not needed any more
+            delete_ref(store, key, &mut remote, &remote_branch, &mut push_opts);
+        }
+    }
+    for (checks_url, remote_branch, test) in patch_tests {
+        for result in test.join().unwrap() {
+            patch_results.push((checks_url.clone(), result));
+        }
+        delete_ref(store, key, &mut remote, &remote_branch, &mut push_opts);
+    }
+
+    if !successfully_applied && !fetch_failed {
+        results.push(apply_failed_result());
+    }
+    store.finish_run(key, &results, &patch_results);
+    (results, patch_results)
+}
+
+/// Finish a test run that was interrupted when snowpatch last stopped: wait
+/// for the Jenkins builds it started, delete the branches it pushed and work
+/// out its results from what it got done.
+fn resume_run(
+    settings: &Config,
+    jenkins: &JenkinsBackend,
+    store: &StateStore,
+    key: &RunKey,
+    project: &Project,
+    run: &TestRecord,
+) -> (Vec<TestResult>, PatchResults) {
+    let repo = project.get_repo().unwrap();
+    let mut remote = repo.find_remote(&project.remote_name).unwrap();
+
+    let mut push_opts = PushOptions::new();
+    push_opts.remote_callbacks(git::remote_callbacks(
+        &settings.git,
+        project.credentials.as_ref(),
+    ));
+
+    let mut results = Vec::new();
+    let mut patch_results = Vec::new();
+    for branch in &run.branches {
+        let base = branch.base.as_ref().map(String::as_str);
+        let tag = match branch.tag {
+            Some(ref tag) => tag,
+            None => {
+                let err = branch.apply_error.as_ref().map_or("", String::as_str);
+                let mut result = apply_result(&branch.branch, base, Err(err));
+                result.target_url = report_url(settings, branch.apply_report.as_ref());
+                results.push(result);
+                continue;
+            }
+        };
+        if branch.checks_url.is_none() {
+            results.push(apply_result(&branch.branch, base, Ok(())));
+        }
+
+        for build in &branch.builds {
+            let job = match project.jobs.iter().find(|job| job.title == build.job) {
+                Some(job) => job,
+                None => {
+                    warn!("Job {} is no longer configured, ignoring it", build.job);
+                    continue;
+                }
+            };
+            let build_results = match (&build.queue_url, build.finished) {
+                (&Some(ref queue_url), _) => {
+                    info!("Waiting for Jenkins job {}/{}", branch.branch, job.title);
+                    let queued = build.queued.unwrap_or_else(utils::timestamp);
+                    let started = build.started.unwrap_or(queued);
+                    wait_for_build(
+                        jenkins,
+                        store,
+                        key,
+                        job,
+                        &branch.branch,
+                        base,
+                        queue_url,
+                        queued,
+                        build.build_url.clone().map(|url| (url, started)),
+                    )
+                }
+                // We stopped before we got round to queuing it
+                (&None, None) => {
+                    start_build(jenkins, store, key, project, job, tag, &branch.branch, base)
+                }
+                // The branch was gone before it could be queued
+                (&None, Some(_)) => vec![not_run_result(job, &branch.branch, base)],
+            };
+            for result in build_results {
+                match branch.checks_url {
+                    Some(ref checks_url) => patch_results.push((checks_url.clone(), result)),
+                    None => results.push(result),
+                }
+            }
+        }
+
+        // Delete the remote branch now it's not needed any more
+        if branch.deleted.is_none() {
+            delete_ref(store, key, &mut remote, tag, &mut push_opts);
+        }
+    }
+
+    if run.branches.iter().all(|branch| branch.tag.is_none()) {
+        results.push(apply_failed_result());
+    }
+    (results, patch_results)
+}
+
+/// Pick up the test runs that were interrupted when snowpatch last stopped,
+/// and report their results.
+fn resume_runs(
+    settings: &Config,
+    jenkins: &Arc<JenkinsBackend>,
+    store: &Arc<StateStore>,
+    patchwork: &PatchworkServer,
+    outbox: &Option<Arc<Outbox>>,
+    scheduler: &Scheduler,
+    runs: Vec<(RunKey, TestRecord)>,
+) {
+    for (key, run) in runs {
+        let (name, project) = match run.project.clone().and_then(|name| {
+            let project = settings.projects.get(&name).cloned();
+            project.map(|project| (name, project))
+        }) {
+            Some(found) => found,
+            None => {
+                warn!("Can't resume test run for {}: unknown project", key);
+                continue;
+            }
+        };
+        info!("Resuming test run for {}", key);
+
+        let settings = settings.clone();
+        let jenkins = jenkins.clone();
+        let store = store.clone();
+        let patchwork = patchwork.clone();
+        let outbox = outbox.clone();
+        let thread_name = format!("resumed {}", key);
+        scheduler.spawn(&name, thread_name, move |ticket| {
+            let (results, patch_results) =
+                resume_run(&settings, &jenkins, &store, &key, &project, &run);
+            store.finish_run(&key, &results, &patch_results);
+
+            // Report results in the order the runs were scheduled
+            ticket.wait_turn();
+            if let Some(ref checks_url) = run.checks_url {
+                post_results(
+                    &patchwork,
+                    outbox.as_ref().map(|outbox| &**outbox),
+                    &project,
+                    results,
+                    checks_url,
+                    &run.series_checks,
+                    patch_results,
+                );
+            }
         });
     }
+}
+
+// Whether a patch from Patchwork still needs to be tested
+fn needs_testing(patch: &Patch, store: &StateStore, project: &Project, project_name: &str) -> bool {
+    // If it's already been tested, we can skip it
+    if patch.check != TestState::Pending {
+        debug!("Skipping already tested patch {}", patch.name);
+        return false;
+    }
+
+    // Patchwork won't know about it if we didn't push the results
+    if store.contains(&RunKey::Patch(patch.id)) {
+        debug!("Skipping patch {} we've tested before", patch.name);
+        return false;
+    }
+
+    if !project.is_testable(&patch.state) {
+        debug!("Skipping patch {} in state {}", patch.name, patch.state);
+        return false;
+    }
+
+    // Skip if it's the wrong project
+    if patch.project.link_name != project_name {
+        warn!(
+            "Skipping patch {} ({}) (wrong project: {})",
+            patch.name, patch.id, patch.project.link_name
+        );
+        return false;
+    }
+
+    true
+}
+
+// A patch from Patchwork, downloaded and ready to be tested
+struct PatchworkTest {
+    patch: Patch,
+    mbox: PathBuf,
+    hefty_tests: bool,
+    series_checks: Vec<String>,
+    // The checks URL of every patch in the mbox, if it's the whole series
+    patch_checks: Vec<String>,
+    // The commit the series says it's based on, if it says
+    base: Option<String>,
+}
+
+// What came of fetching a patch from Patchwork
+enum Fetched {
+    // It's ready to be tested
+    Test(PatchworkTest),
+    // Its series hasn't been fully received yet, so it should be tried again
+    // later
+    Incomplete,
+    // It's tested along with the last patch in its series, when testing a
+    // series a patch at a time
+    WithSeries,
+}
+
+/// Download a patch from Patchwork, along with the patches before it in its
+/// series, ready to be tested.
+///
+/// With `bisect`, patches before the last in a series are tested along with
+/// the last one, so aren't downloaded at all.
+fn fetch_patchwork_patch(
+    patchwork: &PatchworkServer,
+    patch: &Patch,
+    bisect: bool,
+) -> Result<Fetched, PatchworkError> {
+    // TODO(ajd): Refactor this.
+    let hefty_tests;
+    let mut series_checks = Vec::new();
+    let mut patch_checks = Vec::new();
+    let mut base = None;
+    let mbox = if patch.has_series() {
+        debug!(
+            "Patch {} has a series at {}!",
+            &patch.name, &patch.series[0].url
+        );
+        let series = patchwork.get_series_by_url(&patch.series[0].url);
+        match series {
+            Ok(series) => {
+                if !series.received_all {
+                    debug!("Series is incomplete, skipping patch for now");
+                    return Ok(Fetched::Incomplete);
+                }
+                let dependencies = patchwork.get_patch_dependencies(patch)?;
+                hefty_tests = dependencies.len() == series.patches.len();
+                if bisect && !hefty_tests {
+                    debug!("Leaving patch {} to be tested with its series", patch.name);
+                    return Ok(Fetched::WithSeries);
+                }
+                if hefty_tests {
+                    series_checks = checks_urls_except(&dependencies, patch);
+                    patch_checks = dependencies.iter().map(|p| p.checks.clone()).collect();
+                }
+                base = patchwork.get_series_base(&series);
+                patchwork.get_patches_mbox(dependencies)?
+            }
+            Err(e) => {
+                debug!("Series is not OK: {}", e);
+                hefty_tests = true;
+                patchwork.get_patch_mbox(patch)?
+            }
+        }
+    } else {
+        hefty_tests = true;
+        patchwork.get_patch_mbox(patch)?
+    };
+
+    Ok(Fetched::Test(PatchworkTest {
+        patch: patch.clone(),
+        mbox: mbox,
+        hefty_tests: hefty_tests,
+        series_checks: series_checks,
+        patch_checks: patch_checks,
+        base: base,
+    }))
+}
+
+// What the refs pushed for a run are named after, given the ID and version
+// of the series being tested if there is one
+fn ref_names(project: &str, key: &RunKey, series: Option<(u64, u64)>) -> RefNames {
+    RefNames {
+        project: project.to_string(),
+        patch_id: match *key {
+            RunKey::Patch(id) => Some(id),
+            _ => None,
+        },
+        series_id: series.map(|series| series.0),
+        version: series.map(|series| series.1),
+        run: utils::sanitise_path(key.to_string()),
+    }
+}
+
+/// Test a patch downloaded from Patchwork.  The run must already have been
+/// started in the state store.
+fn test_patchwork_patch(
+    settings: &Config,
+    jenkins: &Arc<JenkinsBackend>,
+    store: &Arc<StateStore>,
+    git_lock: &Mutex<()>,
+    project: &Project,
+    test: &PatchworkTest,
+) -> (Vec<TestResult>, PatchResults) {
+    let key = RunKey::Patch(test.patch.id);
+    let series = test.patch.series.first();
+    let refs = ref_names(
+        &test.patch.project.link_name,
+        &key,
+        series.map(|series| (series.id, series.version)),
+    );
+    let bisect: &[String] = if project.bisect.unwrap_or(false) {
+        &test.patch_checks
+    } else {
+        &[]
+    };
+    let results = test_patch(
+        settings,
+        jenkins,
+        store,
+        git_lock,
+        &key,
+        project,
+        &test.mbox,
+        &refs,
+        test.hefty_tests,
+        bisect,
+        test.base.as_ref().map(String::as_str),
+    );
+
+    // Delete the temporary directory with the patch in it
+    fs::remove_dir_all(test.mbox.parent().unwrap())
+        .unwrap_or_else(|err| error!("Couldn't delete temp directory: {}", err));
     results
 }
 
+// The checks URLs of every patch in a series other than the one we tested
+fn checks_urls_except(series: &[Patch], patch: &Patch) -> Vec<String> {
+    series
+        .iter()
+        .filter(|p| p.id != patch.id)
+        .map(|p| p.checks.clone())
+        .collect()
+}
+
+fn post_result(patchwork: &PatchworkServer, checks_url: &str, result: TestResult) {
+    if let Err(err) = patchwork.post_test_result(result, checks_url) {
+        error!("Couldn't post result to {}: {}", checks_url, err);
+    }
+}
+
+/// Report the results of a test run to Patchwork, if the project wants us to.
+///
+/// If the whole of a series was tested, `series_checks` holds the checks URLs
+/// of the other patches in it, which get the results selected by the
+/// project's `series_checks` setting.
+///
+/// `patch_results` are posted as they are to the patches they're for.
+///
+/// With an outbox, results are saved there first and anything Patchwork
+/// doesn't accept is left for the outbox sender to try again.
+#[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+fn post_results(
+    patchwork: &PatchworkServer,
+    outbox: Option<&Outbox>,
+    project: &Project,
+    mut results: Vec<TestResult>,
+    checks_url: &str,
+    series_checks: &[String],
+    patch_results: PatchResults,
+) {
+    if !project.push_results {
+        return;
+    }
+
+    if project.aggregate_results.unwrap_or(false) {
+        let overall = report::aggregate(&results);
+        results.push(overall);
+    }
+
+    let series_results = match project.series_checks {
+        Some(ref checks) if !series_checks.is_empty() => report::series_results(&results, checks),
+        _ => vec![],
+    };
+
+    let mut posts: Vec<(&str, TestResult)> = results
+        .into_iter()
+        .map(|result| (checks_url, result))
+        .collect();
+    for url in series_checks {
+        for result in &series_results {
+            posts.push((url, result.clone()));
+        }
+    }
+    for &(ref url, ref result) in &patch_results {
+        posts.push((url, result.clone()));
+    }
+
+    match outbox {
+        Some(outbox) => {
+            for (url, result) in posts {
+                if let Err(err) = outbox.push(url, result.clone()) {
+                    error!("Couldn't add result to the outbox: {}", err);
+                    post_result(patchwork, url, result);
+                }
+            }
+            if let Err(err) = outbox.flush(patchwork) {
+                error!("Couldn't flush the outbox: {}", err);
+            }
+        }
+        None => {
+            for (url, result) in posts {
+                post_result(patchwork, url, result);
+            }
+        }
+    }
+}
+
+// The patches an event from the Patchwork event stream asks us to look at
+fn event_patches(patchwork: &PatchworkServer, event: &Event) -> Vec<Patch> {
+    let urls = match event.category.as_str() {
+        // Every patch in the series is now available, so test them all, just
+        // like we would when scanning the patch list
+        "series-completed" => match event.payload.series {
+            Some(ref series) => match patchwork.get_series_by_url(&series.url) {
+                Ok(series) => series.patches.iter().map(|p| p.url.clone()).collect(),
+                Err(e) => {
+                    warn!("Couldn't get series for event {}: {}", event.id, e);
+                    vec![]
+                }
+            },
+            None => vec![],
+        },
+        // A patch may have just moved into a state we test
+        "patch-state-changed" => match event.payload.patch {
+            Some(ref patch) => vec![patch.url.clone()],
+            None => vec![],
+        },
+        // New patches are picked up once their series is complete
+        _ => {
+            debug!("Ignoring {} event {}", event.category, event.id);
+            vec![]
+        }
+    };
+
+    urls.iter()
+        .filter_map(|url| match patchwork.get_patch_by_url(url) {
+            Ok(patch) => Some(patch),
+            Err(e) => {
+                warn!("Couldn't get patch {} for event {}: {}", url, event.id, e);
+                None
+            }
+        })
+        .collect()
+}
+
 #[cfg_attr(feature = "cargo-clippy", allow(cyclomatic_complexity))]
 fn main() {
     let mut log_builder = Builder::new();
@@ -333,13 +1381,111 @@ fn main() {
         }
     });
 
+    // Every test shares one connection to Jenkins, and so one crumb
+    let jenkins = Arc::new(JenkinsBackend::new(
+        &settings.jenkins.url,
+        client.clone(),
+        settings.jenkins.username.clone(),
+        settings.jenkins.token.clone(),
+    ));
+
     let mut patchwork = PatchworkServer::new(&settings.patchwork.url, &client);
     patchwork.set_authentication(
         &settings.patchwork.user,
         &settings.patchwork.pass,
         &settings.patchwork.token,
     );
-    let patchwork = patchwork;
+    patchwork.set_pagination(settings.patchwork.page_size, settings.patchwork.max_pages);
+    let default_retries = RetryPolicy::default();
+    patchwork.set_retry_policy(RetryPolicy {
+        max_retries: settings
+            .patchwork
+            .retries
+            .unwrap_or(default_retries.max_retries),
+        initial_delay: settings
+            .patchwork
+            .retry_delay
+            .map_or(default_retries.initial_delay, Duration::from_secs),
+        max_delay: settings
+            .patchwork
+            .max_retry_delay
+            .map_or(default_retries.max_delay, Duration::from_secs),
+    });
+    let mut outbox = None;
+    let mut store = StateStore::in_memory();
+    if let Some(ref state) = settings.state {
+        fs::create_dir_all(&state.directory)
+            .unwrap_or_else(|err| panic!("Couldn't create state directory: {}", err));
+        patchwork.load_state(&Path::new(&state.directory).join("patchwork.json"));
+        outbox = Some(Arc::new(Outbox::open(
+            &Path::new(&state.directory).join("outbox.jsonl"),
+        )));
+        store = StateStore::open(&Path::new(&state.directory).join("tested.db"));
+    }
+    let store = Arc::new(store);
+
+    if args.cmd_outbox {
+        let outbox = outbox.unwrap_or_else(|| panic!("The outbox needs a [state] section"));
+        if args.cmd_list {
+            let entries = outbox
+                .entries()
+                .unwrap_or_else(|err| panic!("Couldn't read the outbox: {}", err));
+            for entry in entries {
+                println!(
+                    "{}\t{}\t{:?}\t{}\t{} attempts\t{}",
+                    entry.id,
+                    entry.result.context.unwrap_or_default(),
+                    entry.result.state,
+                    entry.checks_url,
+                    entry.attempts,
+                    entry.last_error.unwrap_or_default()
+                );
+            }
+        } else if args.cmd_flush {
+            patchwork.negotiate_api_version();
+            let remaining = outbox
+                .flush(&patchwork)
+                .unwrap_or_else(|err| panic!("Couldn't flush the outbox: {}", err));
+            println!("{} results still waiting to be posted", remaining);
+        } else if args.cmd_drop {
+            let ids = if args.flag_all {
+                None
+            } else {
+                Some(&args.arg_result_id[..])
+            };
+            let dropped = outbox
+                .drop_entries(ids)
+                .unwrap_or_else(|err| panic!("Couldn't drop results: {}", err));
+            println!("Dropped {} results", dropped);
+        }
+        return;
+    }
+
+    if args.cmd_gc {
+        if settings.state.is_none() {
+            panic!("gc needs a [state] section");
+        }
+        collect_garbage(&settings, &store, args.flag_dry_run);
+        return;
+    }
+
+    info!(
+        "snowpatch is using Patchwork API version {}",
+        patchwork.negotiate_api_version()
+    );
+
+    // Set up the mirrors of any projects that want snowpatch to manage them
+    for project in settings.projects.values() {
+        if let Some(ref url) = project.clone_url {
+            git::init_mirror(
+                url,
+                Path::new(&project.repository),
+                &project.remote_name,
+                &project.remote_uri,
+            )
+            .unwrap_or_else(|err| panic!("Couldn't set up mirror of {}: {}", url, err));
+        }
+    }
 
     if args.flag_series > 0 && args.flag_patch > 0 {
         panic!("Can't specify both --series and --patch");
@@ -347,17 +1493,50 @@ fn main() {
 
     if args.flag_patch > 0 {
         info!("snowpatch is testing a patch from Patchwork.");
-        let patch = patchwork.get_patch(&(args.flag_patch as u64)).unwrap();
+        let patch = patchwork
+            .get_patch(&(args.flag_patch as u64))
+            .unwrap_or_else(|err| panic!("Couldn't get patch: {}", err));
         match settings.projects.get(&patch.project.link_name) {
             None => panic!("Couldn't find project {}", &patch.project.link_name),
             Some(project) => {
                 let mbox = if patch.has_series() {
-                    let dependencies = patchwork.get_patch_dependencies(&patch);
-                    patchwork.get_patches_mbox(dependencies)
+                    patchwork
+                        .get_patch_dependencies(&patch)
+                        .and_then(|dependencies| patchwork.get_patches_mbox(dependencies))
                 } else {
                     patchwork.get_patch_mbox(&patch)
-                };
-                test_patch(&settings, &client, project, &mbox, true);
+                }
+                .unwrap_or_else(|err| panic!("Couldn't get mbox: {}", err));
+                let base = patch.series.first().and_then(|series| {
+                    match patchwork.get_series_by_url(&series.url) {
+                        Ok(series) => patchwork.get_series_base(&series),
+                        Err(err) => {
+                            warn!("Couldn't get series {}: {}", series.id, err);
+                            None
+                        }
+                    }
+                });
+                let key = RunKey::Patch(patch.id);
+                store
+                    .start_run(&key, &patch.project.link_name, None, &[])
+                    .unwrap_or_else(|err| panic!("Couldn't record test run: {}", err));
+                let series = patch
+                    .series
+                    .first()
+                    .map(|series| (series.id, series.version));
+                test_patch(
+                    &settings,
+                    &jenkins,
+                    &store,
+                    &Mutex::new(()),
+                    &key,
+                    project,
+                    &mbox,
+                    &ref_names(&patch.project.link_name, &key, series),
+                    true,
+                    &[],
+                    base.as_ref().map(String::as_str),
+                );
             }
         }
         return;
@@ -365,28 +1544,72 @@ fn main() {
 
     if args.flag_series > 0 {
         info!("snowpatch is testing a series from Patchwork.");
-        let series = patchwork.get_series(&(args.flag_series as u64)).unwrap();
+        let series = patchwork
+            .get_series(&(args.flag_series as u64))
+            .unwrap_or_else(|err| panic!("Couldn't get series: {}", err));
         // The last patch in the series, so its dependencies are the whole series
         let patch = patchwork
             .get_patch_by_url(&series.patches.last().unwrap().url)
-            .unwrap();
+            .unwrap_or_else(|err| panic!("Couldn't get patch: {}", err));
         // We have to do it this way since there's no project field on Series
-        let project = patchwork.get_project(&patch.project.name).unwrap();
+        let project = patchwork
+            .get_project(&patch.project.name)
+            .unwrap_or_else(|err| panic!("Couldn't get project: {}", err));
         match settings.projects.get(&project.link_name) {
             None => panic!("Couldn't find project {}", &project.link_name),
             Some(project) => {
-                let dependencies = patchwork.get_patch_dependencies(&patch);
-                let mbox = patchwork.get_patches_mbox(dependencies);
-                let results = test_patch(&settings, &client, project, &mbox, true);
+                let dependencies = patchwork
+                    .get_patch_dependencies(&patch)
+                    .unwrap_or_else(|err| panic!("Couldn't get series patches: {}", err));
+                let series_checks = checks_urls_except(&dependencies, &patch);
+                let patch_checks: Vec<String> = if project.bisect.unwrap_or(false) {
+                    dependencies.iter().map(|p| p.checks.clone()).collect()
+                } else {
+                    vec![]
+                };
+                let mbox = patchwork
+                    .get_patches_mbox(dependencies)
+                    .unwrap_or_else(|err| panic!("Couldn't get mbox: {}", err));
+                let base = patchwork.get_series_base(&series);
+                let key = RunKey::Series(series.id);
+                store
+                    .start_run(
+                        &key,
+                        &patch.project.link_name,
+                        Some(&patch.checks),
+                        &series_checks,
+                    )
+                    .unwrap_or_else(|err| panic!("Couldn't record test run: {}", err));
+                let (results, patch_results) = test_patch(
+                    &settings,
+                    &jenkins,
+                    &store,
+                    &Mutex::new(()),
+                    &key,
+                    project,
+                    &mbox,
+                    &ref_names(
+                        &patch.project.link_name,
+                        &key,
+                        Some((series.id, series.version)),
+                    ),
+                    true,
+                    &patch_checks,
+                    base.as_ref().map(String::as_str),
+                );
 
                 // Delete the temporary directory with the patch in it
                 fs::remove_dir_all(mbox.parent().unwrap())
                     .unwrap_or_else(|err| error!("Couldn't delete temp directory: {}", err));
-                if project.push_results {
-                    for result in results {
-                        patchwork.post_test_result(result, &patch.checks).unwrap();
-                    }
-                }
+                post_results(
+                    &patchwork,
+                    outbox.as_ref().map(|outbox| &**outbox),
+                    project,
+                    results,
+                    &patch.checks,
+                    &series_checks,
+                    patch_results,
+                );
             }
         }
         return;
@@ -398,7 +1621,23 @@ fn main() {
     if args.flag_mbox != "" {
         info!("snowpatch is testing a local patch.");
         let patch = Path::new(&args.flag_mbox);
-        test_patch(&settings, &client, project, patch, true);
+        let key = RunKey::Mbox(args.flag_mbox.clone());
+        store
+            .start_run(&key, &args.flag_project, None, &[])
+            .unwrap_or_else(|err| panic!("Couldn't record test run: {}", err));
+        test_patch(
+            &settings,
+            &jenkins,
+            &store,
+            &Mutex::new(()),
+            &key,
+            project,
+            patch,
+            &ref_names(&args.flag_project, &key, None),
+            true,
+            &[],
+            None,
+        );
 
         return;
     }
@@ -412,80 +1651,286 @@ fn main() {
      * If the patch is part of a series, apply all of its dependencies.
      * Spawn tests.
      */
-    'daemon: loop {
-        let patch_list = patchwork
-            .get_patch_query(&args.flag_project)
-            .unwrap_or_else(|err| panic!("Failed to obtain patch list: {}", err));
-        info!("snowpatch is ready to test new revisions from Patchwork.");
-        for patch in patch_list {
-            // If it's already been tested, we can skip it
-            if patch.check != "pending" {
-                debug!("Skipping already tested patch {}", patch.name);
-                continue;
-            }
+    let use_events = settings.patchwork.use_events.unwrap_or(true) && patchwork.supports_events();
+    if use_events {
+        info!("snowpatch is following the Patchwork event stream.");
+    } else {
+        info!("snowpatch is scanning the Patchwork patch list.");
+    }
 
-            if !patch.action_required() {
-                debug!("Skipping patch {} in state {}", patch.name, patch.state);
-                continue;
+    // Keep trying to post any results Patchwork didn't accept the first time
+    if let Some(ref outbox) = outbox {
+        let outbox = outbox.clone();
+        let patchwork = patchwork.clone();
+        let interval = settings
+            .state
+            .as_ref()
+            .and_then(|state| state.outbox_interval)
+            .unwrap_or(60);
+        thread::spawn(move || loop {
+            if let Err(err) = outbox.flush(&patchwork) {
+                error!("Couldn't flush the outbox: {}", err);
             }
+            thread::sleep(Duration::new(interval, 0));
+        });
+    }
 
-            // Skip if it's the wrong project
-            if patch.project.link_name != args.flag_project {
-                warn!(
-                    "Skipping patch {} ({}) (wrong project: {})",
-                    patch.name, patch.id, patch.project.link_name
+    // Clean up the refs we've pushed that aren't needed any more
+    if let Some(ref state) = settings.state {
+        let settings = settings.clone();
+        let store = store.clone();
+        let interval = state.gc_interval.unwrap_or(3600);
+        thread::spawn(move || loop {
+            collect_garbage(&settings, &store, false);
+            thread::sleep(Duration::new(interval, 0));
+        });
+    }
+
+    // Finish off whatever we were testing when we last stopped.  This has to
+    // be worked out before we start anything new.  Runs that were still
+    // waiting for their turn are started again from scratch.
+    let (unstarted, unfinished): (Vec<_>, Vec<_>) = store
+        .unfinished()
+        .into_iter()
+        .partition(|&(_, ref run)| run.branches.is_empty());
+
+    let mut project_limits = BTreeMap::new();
+    for (name, project) in &settings.projects {
+        if let Some(max_jobs) = project.max_jobs {
+            project_limits.insert(name.clone(), max_jobs);
+        }
+    }
+    let max_jobs = settings
+        .scheduler
+        .as_ref()
+        .and_then(|scheduler| scheduler.max_jobs)
+        .unwrap_or(1);
+    let scheduler = Scheduler::new(max_jobs, project_limits);
+
+    // Resumed runs go through the scheduler ahead of anything new, so they
+    // count towards the job limits and post their results first
+    if !unfinished.is_empty() {
+        info!("Resuming {} interrupted test runs.", unfinished.len());
+        resume_runs(
+            &settings, &jenkins, &store, &patchwork, &outbox, &scheduler, unfinished,
+        );
+    }
+
+    let bisect = project.bisect.unwrap_or(false);
+
+    // Test a patch in the background, once there's room for it.  It's only
+    // scheduled once it's been recorded, so that it can be picked up again if
+    // we stop before it's tested, and the poll marks mustn't pass it if it
+    // wasn't.
+    let schedule = |test: PatchworkTest, patchwork: &PatchworkServer| -> bool {
+        let key = RunKey::Patch(test.patch.id);
+        let started = store.start_run(
+            &key,
+            &test.patch.project.link_name,
+            Some(&test.patch.checks),
+            &test.series_checks,
+        );
+        if let Err(err) = started {
+            error!("Couldn't record test run for {}: {}", key, err);
+            fs::remove_dir_all(test.mbox.parent().unwrap())
+                .unwrap_or_else(|err| error!("Couldn't delete temp directory: {}", err));
+            return false;
+        }
+
+        let settings = settings.clone();
+        let jenkins = jenkins.clone();
+        let store = store.clone();
+        let patchwork = patchwork.clone();
+        let outbox = outbox.clone();
+        let project = project.clone();
+        let git_lock = scheduler.repo_lock(&project.repository);
+        let name = format!("patch {}", test.patch.id);
+        scheduler.spawn(&args.flag_project, name, move |ticket| {
+            let (results, patch_results) =
+                test_patchwork_patch(&settings, &jenkins, &store, &git_lock, &project, &test);
+
+            // Report results in the order the patches were scheduled
+            ticket.wait_turn();
+            post_results(
+                &patchwork,
+                outbox.as_ref().map(|outbox| &**outbox),
+                &project,
+                results,
+                &test.patch.checks,
+                &test.series_checks,
+                patch_results,
+            );
+        });
+        true
+    };
+
+    // Patchwork has to be asked for the patches of runs that never got
+    // started again, as we've already polled past them
+    let mut unstarted: Vec<RunKey> = unstarted
+        .into_iter()
+        .filter(|&(_, ref run)| run.project.as_ref() == Some(&args.flag_project))
+        .map(|(key, _)| key)
+        .collect();
+    let reschedule = |key: &RunKey, patchwork: &PatchworkServer| -> bool {
+        let id = match *key {
+            RunKey::Patch(id) => id,
+            _ => {
+                info!(
+                    "Test run for {} wasn't started by the daemon, forgetting it",
+                    key
                 );
-                continue;
+                store.remove(key);
+                return true;
+            }
+        };
+        let fetched = patchwork
+            .get_patch(&id)
+            .and_then(|patch| fetch_patchwork_patch(patchwork, &patch, bisect));
+        match fetched {
+            Ok(Fetched::Test(test)) => {
+                info!("Testing patch {} again, it never got started", id);
+                schedule(test, patchwork)
+            }
+            // It's tested with the last patch in its series
+            Ok(Fetched::WithSeries) => {
+                store.remove(key);
+                true
             }
+            Ok(Fetched::Incomplete) => false,
+            Err(err) => {
+                error!("Couldn't get patch {} to test it again: {}", id, err);
+                false
+            }
+        }
+    };
 
-            // TODO(ajd): Refactor this.
-            let hefty_tests;
-            let mbox = if patch.has_series() {
-                debug!(
-                    "Patch {} has a series at {}!",
-                    &patch.name, &patch.series[0].url
-                );
-                let series = patchwork.get_series_by_url(&patch.series[0].url);
-                match series {
-                    Ok(series) => {
-                        if !series.received_all {
-                            debug!("Series is incomplete, skipping patch for now");
+    'daemon: loop {
+        // Keep trying the runs we couldn't start again until we can
+        unstarted.retain(|key| !reschedule(key, &patchwork));
+        info!("snowpatch is ready to test new revisions from Patchwork.");
+        // Only list what's dated before this poll started, so a patch whose
+        // mail is dated in the future can't carry the mark past patches that
+        // are yet to arrive.  It's listed once its date has come.
+        let before = utils::iso_date(utils::timestamp());
+        if use_events {
+            // Only ask for events we haven't seen in a previous poll
+            let mut mark = patchwork.event_mark(&args.flag_project);
+            let since = mark.date.clone();
+            let mut processed = Vec::new();
+            let mut deferred = Vec::new();
+            let event_list = patchwork.get_events_query(
+                &args.flag_project,
+                since.as_ref().map(String::as_ref),
+                Some(&before),
+            );
+            for event in event_list {
+                // Keep what we've got so far and try the rest next time
+                let event = match event {
+                    Ok(event) => event,
+                    Err(err) => {
+                        error!("Failed to obtain event list: {}", err);
+                        break;
+                    }
+                };
+
+                if mark.contains(&event.date, event.id) {
+                    debug!("Skipping previously seen event {}", event.id);
+                    continue;
+                }
+                processed.push((event.date.clone(), event.id));
+
+                for patch in event_patches(&patchwork, &event) {
+                    if !needs_testing(&patch, &store, project, &args.flag_project) {
+                        continue;
+                    }
+                    match fetch_patchwork_patch(&patchwork, &patch, bisect) {
+                        Ok(Fetched::Test(test)) => {
+                            if !schedule(test, &patchwork) {
+                                deferred.push((event.date.clone(), event.id));
+                                continue;
+                            }
+                        }
+                        Ok(Fetched::Incomplete) | Ok(Fetched::WithSeries) => continue,
+                        Err(err) => {
+                            error!("Couldn't test patch {}: {}", patch.name, err);
+                            deferred.push((event.date.clone(), event.id));
                             continue;
                         }
-                        let dependencies = patchwork.get_patch_dependencies(&patch);
-                        hefty_tests = dependencies.len() == series.patches.len();
-                        patchwork.get_patches_mbox(dependencies)
                     }
-                    Err(e) => {
-                        debug!("Series is not OK: {}", e);
-                        hefty_tests = true;
-                        patchwork.get_patch_mbox(&patch)
+                    if args.flag_count > 0 {
+                        patch_count += 1;
+                        debug!("Tested {} patches out of {}", patch_count, args.flag_count);
+                        if patch_count >= args.flag_count {
+                            break 'daemon;
+                        }
                     }
                 }
-            } else {
-                hefty_tests = true;
-                patchwork.get_patch_mbox(&patch)
-            };
+            }
+            mark.advance(&processed, &deferred);
+            patchwork.set_event_mark(&args.flag_project, mark);
+        } else {
+            // Only ask for patches we haven't seen in a previous poll.  Patches
+            // are dated by their mail, which can arrive long after it was
+            // sent, so look back a while before the mark for ones that were
+            // held up; needs_testing skips the ones we've already dealt with.
+            let mut mark = patchwork.patch_mark(&args.flag_project);
+            let since = mark.since(settings.patchwork.lookback.unwrap_or(86_400));
+            let mut processed = Vec::new();
+            let mut deferred = Vec::new();
+            let mut listed_all = true;
+            let patch_list = patchwork.get_patch_query(
+                &args.flag_project,
+                since.as_ref().map(String::as_ref),
+                Some(&before),
+            );
+            for patch in patch_list {
+                let patch = match patch {
+                    Ok(patch) => patch,
+                    Err(err) => {
+                        error!("Failed to obtain patch list: {}", err);
+                        listed_all = false;
+                        break;
+                    }
+                };
 
-            let results = test_patch(&settings, &client, project, &mbox, hefty_tests);
+                processed.push((patch.date.clone(), patch.id));
 
-            // Delete the temporary directory with the patch in it
-            fs::remove_dir_all(mbox.parent().unwrap())
-                .unwrap_or_else(|err| error!("Couldn't delete temp directory: {}", err));
-            if project.push_results {
-                for result in results {
-                    patchwork.post_test_result(result, &patch.checks).unwrap();
+                if !needs_testing(&patch, &store, project, &args.flag_project) {
+                    continue;
                 }
-            }
-            if args.flag_count > 0 {
-                patch_count += 1;
-                debug!("Tested {} patches out of {}", patch_count, args.flag_count);
-                if patch_count >= args.flag_count {
-                    break 'daemon;
+                match fetch_patchwork_patch(&patchwork, &patch, bisect) {
+                    Ok(Fetched::Test(test)) => {
+                        if !schedule(test, &patchwork) {
+                            deferred.push((patch.date.clone(), patch.id));
+                            continue;
+                        }
+                    }
+                    Ok(Fetched::Incomplete) => {
+                        deferred.push((patch.date.clone(), patch.id));
+                        continue;
+                    }
+                    Ok(Fetched::WithSeries) => continue,
+                    Err(err) => {
+                        error!("Couldn't test patch {}: {}", patch.name, err);
+                        deferred.push((patch.date.clone(), patch.id));
+                        continue;
+                    }
+                }
+                if args.flag_count > 0 {
+                    patch_count += 1;
+                    debug!("Tested {} patches out of {}", patch_count, args.flag_count);
+                    if patch_count >= args.flag_count {
+                        break 'daemon;
+                    }
                 }
             }
+            mark.advance_newest_first(listed_all, &processed, &deferred);
+            patchwork.set_patch_mark(&args.flag_project, mark);
         }
         info!("Finished testing new revisions, sleeping.");
         thread::sleep(Duration::new(settings.patchwork.polling_interval * 60, 0));
     }
+
+    // Wait for the tests we started before we stopped polling
+    scheduler.wait_idle();
 }
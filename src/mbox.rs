@@ -0,0 +1,695 @@
+//
+// snowpatch - continuous integration for patch-based workflows
+//
+// Copyright (C) 2018 IBM Corporation
+// Authors:
+//     Russell Currey <ruscur@russell.cc>
+//     Andrew Donnellan <andrew.donnellan@au1.ibm.com>
+//
+// This program is free software; you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation; either version 2 of the License, or (at your option)
+// any later version.
+//
+// mbox.rs - split an mbox of patches into the commits they make
+//
+
+/// A patch from an mbox, with everything needed to commit it.
+pub struct MboxPatch {
+    pub author_name: String,
+    pub author_email: String,
+    /// Seconds since the epoch and the timezone offset in minutes, if the
+    /// patch has a date we understand
+    pub date: Option<(i64, i32)>,
+    pub subject: String,
+    /// The commit message, starting with the subject
+    pub message: String,
+    pub diff: Vec<u8>,
+}
+
+// Split into lines without their newlines
+fn lines(text: &[u8]) -> Vec<&[u8]> {
+    let mut lines: Vec<&[u8]> = text.split(|&byte| byte == b'\n').collect();
+    if text.ends_with(b"\n") {
+        lines.pop();
+    }
+    lines
+        .into_iter()
+        .map(|line| {
+            if line.ends_with(b"\r") {
+                &line[..line.len() - 1]
+            } else {
+                line
+            }
+        })
+        .collect()
+}
+
+fn base64_decode(text: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for &byte in text {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => continue,
+        };
+        bits = bits << 6 | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    decoded
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        _ => None,
+    }
+}
+
+// Quoted-printable, as used in bodies, or the "Q" encoding used in headers,
+// where underscores are spaces
+fn qp_decode(text: &[u8], header: bool) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        match text[i] {
+            // A soft line break
+            b'=' if text.get(i + 1) == Some(&b'\n') => i += 1,
+            b'=' => match (
+                text.get(i + 1).cloned().and_then(hex_value),
+                text.get(i + 2).cloned().and_then(hex_value),
+            ) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high << 4 | low);
+                    i += 2;
+                }
+                _ => decoded.push(b'='),
+            },
+            b'_' if header => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+// Text in a charset other than UTF-8 is most likely Latin-1
+fn charset_decode(text: &[u8], charset: &str) -> String {
+    if charset.eq_ignore_ascii_case("utf-8") || charset.eq_ignore_ascii_case("us-ascii") {
+        String::from_utf8_lossy(text).into_owned()
+    } else {
+        text.iter().map(|&byte| byte as char).collect()
+    }
+}
+
+// Decode an RFC 2047 "encoded word" like "=?UTF-8?q?Andr=C3=A9?="
+fn decode_word(word: &str) -> Option<String> {
+    if !word.starts_with("=?") || !word.ends_with("?=") || word.len() < 4 {
+        return None;
+    }
+    let parts: Vec<&str> = word[2..word.len() - 2].splitn(3, '?').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let decoded = match parts[1] {
+        "B" | "b" => base64_decode(parts[2].as_bytes()),
+        "Q" | "q" => qp_decode(parts[2].as_bytes(), true),
+        _ => return None,
+    };
+    Some(charset_decode(&decoded, parts[0]))
+}
+
+// Decode any encoded words in a header
+fn decode_header(value: &str) -> String {
+    let mut decoded = String::new();
+    let mut last_encoded = false;
+    for (i, part) in value.split(' ').enumerate() {
+        let word = decode_word(part);
+        // The space between two encoded words doesn't count
+        if i > 0 && !(last_encoded && word.is_some()) {
+            decoded.push(' ');
+        }
+        last_encoded = word.is_some();
+        decoded.push_str(word.as_ref().map_or(part, String::as_str));
+    }
+    decoded
+}
+
+// Split "Name <email>", "email (Name)" or just "email"
+fn parse_address(from: &str) -> (String, String) {
+    let from = from.trim();
+    if let (Some(open), Some(close)) = (from.rfind('<'), from.rfind('>')) {
+        if open < close {
+            let name = from[..open].trim().trim_matches('"').to_string();
+            return (name, from[open + 1..close].trim().to_string());
+        }
+    }
+    if let (Some(open), Some(close)) = (from.find('('), from.rfind(')')) {
+        if open < close {
+            let name = from[open + 1..close].trim().to_string();
+            return (name, from[..open].trim().to_string());
+        }
+    }
+    (from.to_string(), from.to_string())
+}
+
+// Days from 1970-01-01 to the given date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Parse an RFC 2822 date like "Wed, 11 Jul 2018 14:21:05 +1000"
+fn parse_date(date: &str) -> Option<(i64, i32)> {
+    let mut parts: Vec<&str> = date.split_whitespace().collect();
+    // Skip the day of the week
+    if parts.first().map_or(false, |part| part.ends_with(',')) {
+        parts.remove(0);
+    }
+    if parts.len() < 4 {
+        return None;
+    }
+    let day: i64 = parts[0].parse().ok()?;
+    let months = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let month_name = parts[1].to_lowercase();
+    let month = months.iter().position(|&m| month_name.starts_with(m))? as i64 + 1;
+    let mut year: i64 = parts[2].parse().ok()?;
+    if year < 100 {
+        year += if year < 50 { 2000 } else { 1900 };
+    }
+    let time: Vec<i64> = parts[3]
+        .split(':')
+        .map(|part| part.parse().ok())
+        .collect::<Option<Vec<i64>>>()?;
+    if time.len() < 2 {
+        return None;
+    }
+    let seconds = time[0] * 3600 + time[1] * 60 + time.get(2).cloned().unwrap_or(0);
+
+    let zone = parts.get(4).cloned().unwrap_or("+0000");
+    let offset = match zone.as_bytes().first() {
+        Some(&sign) if (sign == b'+' || sign == b'-') && zone.len() == 5 => {
+            let value: i32 = zone[1..].parse().ok()?;
+            let minutes = value / 100 * 60 + value % 100;
+            if sign == b'-' {
+                -minutes
+            } else {
+                minutes
+            }
+        }
+        // "GMT", "UT" and anything else we don't know
+        _ => 0,
+    };
+
+    let time = days_from_civil(year, month, day) * 86_400 + seconds - i64::from(offset) * 60;
+    Some((time, offset))
+}
+
+fn trim_trailing(line: &str) -> &str {
+    match line.char_indices().rev().find(|&(_, c)| !c.is_whitespace()) {
+        Some((end, c)) => &line[..end + c.len_utf8()],
+        None => "",
+    }
+}
+
+// Drop "Re:" and anything in brackets, like "[PATCH v2 3/5]", from the
+// start of a subject, like "git am" does
+fn clean_subject(subject: &str) -> String {
+    let mut subject = subject.trim();
+    loop {
+        // The first three bytes may not be whole characters
+        if subject
+            .get(..3)
+            .map_or(false, |start| start.eq_ignore_ascii_case("re:"))
+        {
+            subject = subject[3..].trim();
+        } else if subject.starts_with('[') {
+            match subject.find(']') {
+                Some(close) => subject = subject[close + 1..].trim(),
+                None => break,
+            }
+        } else {
+            break;
+        }
+    }
+    subject.to_string()
+}
+
+// Headers, unfolded, and the body
+fn parse_mail(mail: &[&[u8]]) -> (Vec<(String, String)>, Vec<u8>) {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    let mut i = 0;
+    while i < mail.len() && !mail[i].is_empty() {
+        let line = String::from_utf8_lossy(mail[i]);
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(last) = headers.last_mut() {
+                last.1.push(' ');
+                last.1.push_str(line.trim());
+            }
+        } else if let Some(colon) = line.find(':') {
+            headers.push((
+                line[..colon].trim().to_lowercase(),
+                line[colon + 1..].trim().to_string(),
+            ));
+        }
+        i += 1;
+    }
+    let body = mail[(i + 1).min(mail.len())..].join(&b'\n');
+    (headers, body)
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|&&(ref key, _)| key == name)
+        .map(|&(_, ref value)| value.as_str())
+}
+
+// Whether a line starts the diff
+fn starts_diff(line: &[u8], next: Option<&&[u8]>) -> bool {
+    line.starts_with(b"diff --git ")
+        || line.starts_with(b"Index: ")
+        || line.starts_with(b"--- ") && next.map_or(false, |next| next.starts_with(b"+++ "))
+}
+
+fn parse_patch(mail: &[&[u8]]) -> MboxPatch {
+    let (mut headers, body) = parse_mail(mail);
+    let body = match header(&headers, "content-transfer-encoding").map(str::to_lowercase) {
+        Some(ref encoding) if encoding == "quoted-printable" => qp_decode(&body, false),
+        Some(ref encoding) if encoding == "base64" => base64_decode(&body),
+        _ => body,
+    };
+    let mut body = lines(&body);
+
+    // "git format-patch" puts the author in the body if they didn't send
+    // the patch themselves
+    while let Some(line) = body.first().cloned() {
+        let line = String::from_utf8_lossy(line).into_owned();
+        let lower = line.to_lowercase();
+        if let Some(name) = ["from", "date", "subject"]
+            .iter()
+            .find(|name| lower.starts_with(&format!("{}:", name)))
+        {
+            headers.insert(
+                0,
+                (name.to_string(), line[name.len() + 1..].trim().to_string()),
+            );
+            body.remove(0);
+        } else {
+            break;
+        }
+    }
+    while body.first().map_or(false, |line| line.is_empty()) {
+        body.remove(0);
+    }
+
+    let (author_name, author_email) =
+        parse_address(&decode_header(header(&headers, "from").unwrap_or("")));
+    let subject = clean_subject(&decode_header(header(&headers, "subject").unwrap_or("")));
+    let date = header(&headers, "date").and_then(parse_date);
+
+    // The message runs up to the "---" line, or the diff if there isn't one
+    let end = (0..body.len())
+        .find(|&i| body[i] == b"---" || starts_diff(body[i], body.get(i + 1)))
+        .unwrap_or(body.len());
+    let mut message_lines: Vec<String> = body[..end]
+        .iter()
+        .map(|line| trim_trailing(&String::from_utf8_lossy(line)).to_string())
+        .collect();
+    while message_lines.last().map_or(false, |line| line.is_empty()) {
+        message_lines.pop();
+    }
+    let mut message = subject.clone();
+    message.push('\n');
+    if !message_lines.is_empty() {
+        message.push('\n');
+        message.push_str(&message_lines.join("\n"));
+        message.push('\n');
+    }
+
+    let start = (end..body.len())
+        .find(|&i| starts_diff(body[i], body.get(i + 1)))
+        .unwrap_or(body.len());
+    let mut diff = body[start..].join(&b'\n');
+    diff.push(b'\n');
+
+    MboxPatch {
+        author_name: author_name,
+        author_email: author_email,
+        date: date,
+        subject: subject,
+        message: message,
+        diff: diff,
+    }
+}
+
+// Whether a line is the "From " line that starts a message in an mbox, like
+// "From 60cc43fc888428bb2f18f08997432d426a243338 Mon Sep 17 00:00:00 2001":
+// "From ", the sender, then a date like asctime() gives.  Other lines that
+// start with "From ", as commit messages can, don't count, like in "git
+// mailsplit".
+fn is_from_line(line: &[u8]) -> bool {
+    if !line.starts_with(b"From ") {
+        return false;
+    }
+    let line = String::from_utf8_lossy(&line[5..]);
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    // The sender and five parts of the date
+    if parts.len() < 6 {
+        return false;
+    }
+    let date = &parts[parts.len() - 5..];
+    let is_name = |part: &str| part.len() == 3 && part.chars().all(|c| c.is_ascii_alphabetic());
+    let is_number = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+    let time: Vec<&str> = date[3].split(':').collect();
+    is_name(date[0])
+        && is_name(date[1])
+        && is_number(date[2])
+        && (time.len() == 2 || time.len() == 3)
+        && time.iter().all(|&part| is_number(part))
+        && date[4].len() == 4
+        && is_number(date[4])
+}
+
+// Lines in messages that start with "From ", after any number of ">", have
+// another ">" in front of them in the mbox
+fn unescape_from(line: &[u8]) -> &[u8] {
+    let quoted = line.iter().take_while(|&&byte| byte == b'>').count();
+    if quoted > 0 && line[quoted..].starts_with(b"From ") {
+        &line[1..]
+    } else {
+        line
+    }
+}
+
+/// Split an mbox into its patches, in order.  Messages without a diff, like
+/// cover letters, are left out.
+pub fn parse(mbox: &[u8]) -> Vec<MboxPatch> {
+    let mut mails: Vec<Vec<&[u8]>> = Vec::new();
+    for line in lines(mbox) {
+        let separator = is_from_line(line);
+        if separator || mails.is_empty() {
+            mails.push(Vec::new());
+        }
+        if !separator {
+            mails.last_mut().unwrap().push(unescape_from(line));
+        }
+    }
+    mails
+        .iter()
+        .map(|mail| parse_patch(mail))
+        .filter(|patch| patch.diff.len() > 1)
+        .collect()
+}
+
+/// The first "base-commit:" line in an mbox, as added by "git format-patch
+/// --base", which puts it in the cover letter if there is one, or otherwise
+/// after the first patch.
+pub fn base_commit(mbox: &str) -> Option<&str> {
+    let prefix = "base-commit:";
+    mbox.lines()
+        .find(|line| line.starts_with(prefix))
+        .map(|line| line[prefix.len()..].trim())
+}
+
+#[cfg(test)]
+mod test {
+    use mbox::*;
+
+    static MBOX: &'static [u8] = b"\
+From patchwork Wed Jul 11 04:21:05 2018
+From: =?utf-8?q?Andr=C3=A9_Example?= <andre@example.com>
+Subject: [PATCH v2 1/2] hello: say hello to
+ the world
+Date: Wed, 11 Jul 2018 14:21:05 +1000
+
+Saying hello to the world is friendlier.
+
+Signed-off-by: Andre Example <andre@example.com>
+---
+ hello.c | 2 +-
+ 1 file changed, 1 insertion(+), 1 deletion(-)
+
+diff --git a/hello.c b/hello.c
+--- a/hello.c
++++ b/hello.c
+@@ -1 +1 @@
+-hello
++hello, world
+--
+2.17.1
+
+From patchwork Wed Jul 11 04:21:06 2018
+From: Someone Else <someone@example.com>
+Subject: [PATCH v2 2/2] Add NEWS
+Date: Wed, 11 Jul 2018 14:21:06 +1000
+
+From: Mary Smith <mary@example.com>
+
+diff --git a/NEWS b/NEWS
+new file mode 100644
+--- /dev/null
++++ b/NEWS
+@@ -0,0 +1 @@
++Now says hello to the world
+";
+
+    #[test]
+    fn parse_patches() {
+        let patches = parse(MBOX);
+        assert_eq!(patches.len(), 2);
+
+        assert_eq!(patches[0].author_name, "Andr\u{e9} Example");
+        assert_eq!(patches[0].author_email, "andre@example.com");
+        assert_eq!(patches[0].date, Some((1_531_282_865, 600)));
+        assert_eq!(patches[0].subject, "hello: say hello to the world");
+        assert_eq!(
+            patches[0].message,
+            "hello: say hello to the world\n\n\
+             Saying hello to the world is friendlier.\n\n\
+             Signed-off-by: Andre Example <andre@example.com>\n"
+        );
+        assert!(patches[0]
+            .diff
+            .starts_with(b"diff --git a/hello.c b/hello.c\n"));
+
+        assert_eq!(patches[1].author_name, "Mary Smith");
+        assert_eq!(patches[1].message, "Add NEWS\n");
+        assert!(patches[1].diff.ends_with(b"+Now says hello to the world\n"));
+    }
+
+    #[test]
+    fn base_commit_from_mbox() {
+        let mbox = "Subject: [PATCH 2/2] foo: fix bar\n\
+                    \n\
+                    -- \n\
+                    2.17.1\n\
+                    \n\
+                    base-commit: 60cc43fc888428bb2f18f08997432d426a243338\n\
+                    prerequisite-patch-id: 3d9d4a6d4d4b4c7d0f4b8e0d1f4e1e7c0b0a4d2a\n";
+        assert_eq!(
+            base_commit(mbox),
+            Some("60cc43fc888428bb2f18f08997432d426a243338")
+        );
+        assert_eq!(base_commit("Subject: [PATCH] foo\n"), None);
+    }
+
+    #[test]
+    fn from_lines() {
+        assert!(is_from_line(
+            b"From 60cc43fc888428bb2f18f08997432d426a243338 Mon Sep 17 00:00:00 2001"
+        ));
+        // Patchwork's mboxes, with the day padded to two characters
+        assert!(is_from_line(b"From patchwork Thu Jan  1 00:00:00 1970"));
+        assert!(!is_from_line(b"From the docs, it looks like this is fine."));
+        assert!(!is_from_line(b"From: Andre Example <andre@example.com>"));
+        assert!(!is_from_line(b">From patchwork Thu Jan  1 00:00:00 1970"));
+    }
+
+    #[test]
+    fn parse_from_in_message() {
+        let mbox = b"\
+From patchwork Wed Jul 11 04:21:05 2018
+From: Andre Example <andre@example.com>
+Subject: [PATCH] hello: be quieter
+
+Nobody asked.
+
+From the docs, saying hello is optional.
+
+>From now on we don't.
+>>From a great height.
+
+diff --git a/hello.c b/hello.c
+--- a/hello.c
++++ b/hello.c
+@@ -1 +1 @@
+-hello, world
++hello
+";
+        let patches = parse(mbox);
+        assert_eq!(patches.len(), 1);
+        assert_eq!(
+            patches[0].message,
+            "hello: be quieter\n\n\
+             Nobody asked.\n\n\
+             From the docs, saying hello is optional.\n\n\
+             From now on we don't.\n\
+             >From a great height.\n"
+        );
+        assert!(patches[0].diff.ends_with(b"+hello\n"));
+    }
+
+    #[test]
+    fn headers() {
+        assert_eq!(
+            decode_header("=?UTF-8?B?QW5kcsOp?= =?ISO-8859-1?Q?_Ex=E1mple?= <a@example.com>"),
+            "Andr\u{e9} Ex\u{e1}mple <a@example.com>"
+        );
+        assert_eq!(
+            decode_header("=?utf-8?x?nope?= plain"),
+            "=?utf-8?x?nope?= plain"
+        );
+        assert_eq!(
+            parse_address("\"Example, Andre\" <andre@example.com>"),
+            (
+                "Example, Andre".to_string(),
+                "andre@example.com".to_string()
+            )
+        );
+        assert_eq!(
+            parse_address("andre@example.com (Andre Example)"),
+            ("Andre Example".to_string(), "andre@example.com".to_string())
+        );
+        assert_eq!(
+            parse_address("andre@example.com"),
+            (
+                "andre@example.com".to_string(),
+                "andre@example.com".to_string()
+            )
+        );
+        assert_eq!(
+            clean_subject("Re: [PATCH v3 04/10] [RFC] powerpc: fix ]brackets["),
+            "powerpc: fix ]brackets["
+        );
+        // Subjects whose third byte is in the middle of a character
+        assert_eq!(clean_subject("Ответ: патч"), "Ответ: патч");
+        assert_eq!(clean_subject("Re: [PATCH] a修正"), "a修正");
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(
+            parse_date("Wed, 11 Jul 2018 14:21:05 +1000"),
+            Some((1_531_282_865, 600))
+        );
+        assert_eq!(
+            parse_date("11 Jul 2018 00:21:05 -0330"),
+            Some((1_531_281_065, -210))
+        );
+        // Two digit years, no seconds and named zones
+        assert_eq!(parse_date("Thu, 1 Jan 70 00:01 GMT"), Some((60, 0)));
+        assert_eq!(
+            parse_date("29 Feb 2000 12:00:00 +0000"),
+            Some((951_825_600, 0))
+        );
+        assert_eq!(parse_date("sometime last week"), None);
+    }
+
+    #[test]
+    fn parse_encoded_bodies() {
+        let mbox = b"\
+From patchwork Wed Jul 11 04:21:05 2018\r
+From: Andre Example <andre@example.com>\r
+Subject: [PATCH 1/2] hello: =?utf-8?q?fran=C3=A7ais?=\r
+Content-Type: text/plain; charset=utf-8\r
+Content-Transfer-Encoding: quoted-printable\r
+\r
+Bonjour =C3=A0 tous, with a line that is long enough to need a soft line br=\r
+eak.\r
+\r
+diff --git a/hello.c b/hello.c\r
+--- a/hello.c\r
++++ b/hello.c\r
+@@ -1 +1 @@\r
+-hello\r
++bonjour a=3Db\r
+\r
+From patchwork Wed Jul 11 04:21:06 2018
+From: Andre Example <andre@example.com>
+Subject: [PATCH 2/2] hello: be shorter
+Content-Transfer-Encoding: base64
+
+ZGlmZiAtLWdpdCBhL2hlbGxvLmMgYi9oZWxsby5jCi0tLSBhL2hlbGxvLmMKKysrIGIvaGVsbG8u
+YwpAQCAtMSArMSBAQAotYm9uam91ciBhPWIKK2hpCg==
+";
+        let patches = parse(mbox);
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].subject, "hello: fran\u{e7}ais");
+        assert_eq!(
+            patches[0].message,
+            "hello: fran\u{e7}ais\n\n\
+             Bonjour \u{e0} tous, with a line that is long enough to need a soft \
+             line break.\n"
+        );
+        assert!(patches[0].diff.ends_with(b"-hello\n+bonjour a=b\n"));
+        assert_eq!(patches[1].message, "hello: be shorter\n");
+        assert_eq!(
+            patches[1].diff,
+            &b"diff --git a/hello.c b/hello.c\n--- a/hello.c\n+++ b/hello.c\n\
+               @@ -1 +1 @@\n-bonjour a=b\n+hi\n"[..]
+        );
+    }
+
+    #[test]
+    fn parse_cover_letter_and_plain_diffs() {
+        // A cover letter, then a patch made with plain "diff -u" and no
+        // "---" line, without a From_ line of its own
+        let mbox = b"\
+From patchwork Wed Jul 11 04:21:04 2018
+From: Andre Example <andre@example.com>
+Subject: [PATCH 0/1] Say hello
+
+Some words about the series.
+
+base-commit: 60cc43fc888428bb2f18f08997432d426a243338
+
+From patchwork Wed Jul 11 04:21:05 2018
+From: Andre Example <andre@example.com>
+Subject: Re: [PATCH 1/1] hello: say hello
+
+Signed-off-by: Andre Example <andre@example.com>
+--- hello.c.orig
++++ hello.c
+@@ -1 +1 @@
+-hello
++hello, world
+";
+        let patches = parse(mbox);
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0].subject, "hello: say hello");
+        assert_eq!(patches[0].date, None);
+        assert_eq!(
+            patches[0].message,
+            "hello: say hello\n\nSigned-off-by: Andre Example <andre@example.com>\n"
+        );
+        assert!(patches[0].diff.starts_with(b"--- hello.c.orig\n"));
+    }
+}
@@ -0,0 +1,250 @@
+//
+// snowpatch - continuous integration for patch-based workflows
+//
+// Copyright (C) 2018 IBM Corporation
+// Authors:
+//     Russell Currey <ruscur@russell.cc>
+//     Andrew Donnellan <andrew.donnellan@au1.ibm.com>
+//
+// This program is free software; you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation; either version 2 of the License, or (at your option)
+// any later version.
+//
+// outbox.rs - test results waiting to be posted to Patchwork
+//
+
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde_json;
+
+use patchwork::{PatchworkServer, TestResult};
+use utils;
+
+/// A test result that hasn't been accepted by Patchwork yet.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OutboxEntry {
+    pub id: u64,
+    pub checks_url: String,
+    pub result: TestResult,
+    // Seconds since the epoch
+    pub queued: u64,
+    #[serde(default)]
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+/// Results are written here before they are posted, so a result from a long
+/// test run isn't lost if Patchwork is unavailable when it finishes.  The
+/// outbox is a file with one JSON entry per line.
+pub struct Outbox {
+    path: PathBuf,
+    // The last ID given to an entry, so IDs aren't reused after the entries
+    // with the highest ones have been posted
+    last_id_path: PathBuf,
+    // Held while reading or writing the file
+    file: Mutex<()>,
+    // Held while posting, so an entry is never posted twice at once
+    delivery: Mutex<()>,
+}
+
+fn invalid_data(err: serde_json::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+impl Outbox {
+    pub fn open(path: &Path) -> Outbox {
+        Outbox {
+            path: path.to_path_buf(),
+            last_id_path: path.with_extension("last_id"),
+            file: Mutex::new(()),
+            delivery: Mutex::new(()),
+        }
+    }
+
+    fn read(&self) -> io::Result<Vec<OutboxEntry>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            entries.push(serde_json::from_str(&line).map_err(invalid_data)?);
+        }
+        Ok(entries)
+    }
+
+    // Replace the whole outbox
+    fn write(&self, entries: &[OutboxEntry]) -> io::Result<()> {
+        let mut contents = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut contents, entry).map_err(invalid_data)?;
+            contents.push(b'\n');
+        }
+        utils::write_atomically(&self.path, &contents)
+    }
+
+    // The outboxes of older versions of snowpatch don't have a last ID, but
+    // it can't be lower than any ID still in the outbox
+    fn last_id(&self, entries: &[OutboxEntry]) -> io::Result<u64> {
+        let saved = match fs::read_to_string(&self.last_id_path) {
+            Ok(id) => id.trim().parse().map_err(|err| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Bad last ID: {}", err))
+            })?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+        Ok(entries.iter().map(|entry| entry.id).fold(saved, cmp::max))
+    }
+
+    /// Every result still waiting to be posted, oldest first.
+    pub fn entries(&self) -> io::Result<Vec<OutboxEntry>> {
+        let _lock = self.file.lock().unwrap();
+        self.read()
+    }
+
+    /// Add a result to the outbox, returning its ID.
+    pub fn push(&self, checks_url: &str, result: TestResult) -> io::Result<u64> {
+        let _lock = self.file.lock().unwrap();
+        let id = self.last_id(&self.read()?)? + 1;
+        utils::write_atomically(&self.last_id_path, format!("{}\n", id).as_bytes())?;
+        let entry = OutboxEntry {
+            id: id,
+            checks_url: checks_url.to_string(),
+            result: result,
+            queued: utils::timestamp(),
+            attempts: 0,
+            last_error: None,
+        };
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut line = serde_json::to_vec(&entry).map_err(invalid_data)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        file.sync_all()?;
+        Ok(id)
+    }
+
+    /// Remove the given entries, or all of them if `ids` is `None`, without
+    /// posting them.  Returns how many were removed.
+    pub fn drop_entries(&self, ids: Option<&[u64]>) -> io::Result<usize> {
+        let _lock = self.file.lock().unwrap();
+        let entries = self.read()?;
+        let count = entries.len();
+        let kept: Vec<OutboxEntry> = match ids {
+            Some(ids) => entries
+                .into_iter()
+                .filter(|entry| !ids.contains(&entry.id))
+                .collect(),
+            None => vec![],
+        };
+        self.write(&kept)?;
+        Ok(count - kept.len())
+    }
+
+    /// Try to post every pending result to Patchwork, removing the ones it
+    /// accepts.  Returns how many are still pending.
+    pub fn flush(&self, patchwork: &PatchworkServer) -> io::Result<usize> {
+        let _delivery = self.delivery.lock().unwrap();
+        let pending = self.entries()?;
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let mut delivered = Vec::new();
+        let mut failed = Vec::new();
+        for entry in &pending {
+            match patchwork.post_test_result(entry.result.clone(), &entry.checks_url) {
+                Ok(_) => delivered.push(entry.id),
+                Err(err) => {
+                    warn!(
+                        "Couldn't post result {} to {}: {}",
+                        entry.id, entry.checks_url, err
+                    );
+                    failed.push((entry.id, err.to_string()));
+                    // If Patchwork is down, the rest will fail too
+                    if err.is_transient() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Results may have been added while we were posting, so start again
+        // from what's in the file now
+        let _lock = self.file.lock().unwrap();
+        let mut entries = self.read()?;
+        entries.retain(|entry| !delivered.contains(&entry.id));
+        for entry in &mut entries {
+            if let Some(&(_, ref err)) = failed.iter().find(|&&(id, _)| id == entry.id) {
+                entry.attempts += 1;
+                entry.last_error = Some(err.clone());
+            }
+        }
+        self.write(&entries)?;
+
+        if !delivered.is_empty() {
+            info!("Posted {} results from the outbox", delivered.len());
+        }
+        Ok(entries.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use outbox::*;
+    use patchwork::TestState;
+    use tempdir::TempDir;
+
+    #[test]
+    fn push_and_drop_entries() {
+        let dir = TempDir::new("snowpatch").unwrap();
+        let outbox = Outbox::open(&dir.path().join("outbox.jsonl"));
+        assert!(outbox.entries().unwrap().is_empty());
+
+        for context in &["apply_patch", "build", "boot"] {
+            outbox
+                .push(
+                    "https://patchwork/api/patches/1/checks/",
+                    TestResult {
+                        state: TestState::Success,
+                        context: Some(context.to_string()),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+        let ids: Vec<u64> = outbox.entries().unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        assert_eq!(outbox.drop_entries(Some(&[2])).unwrap(), 1);
+        let entries = outbox.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].result.context, Some("boot".to_string()));
+
+        assert_eq!(outbox.drop_entries(None).unwrap(), 2);
+        assert!(outbox.entries().unwrap().is_empty());
+
+        // IDs aren't reused, even after a restart
+        let outbox = Outbox::open(&dir.path().join("outbox.jsonl"));
+        let id = outbox
+            .push(
+                "https://patchwork/api/patches/1/checks/",
+                TestResult::default(),
+            )
+            .unwrap();
+        assert_eq!(id, 4);
+    }
+}
@@ -15,30 +15,189 @@
 //
 
 use std;
+use std::cmp;
 use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io;
 use std::option::Option;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result::Result;
+use std::thread;
+use std::time::Duration;
+use std::vec;
+
+use rand::{self, Rng};
 
 use tempdir::TempDir;
 
+use url::form_urlencoded;
+
 use reqwest;
-use reqwest::header::{qitem, Accept, Authorization, Basic, Connection, ContentType, Headers};
+use reqwest::header::{
+    qitem, Accept, Authorization, Basic, Connection, ContentType, Headers, Link, RelationType,
+};
 use reqwest::Client;
 use reqwest::Response;
 use reqwest::StatusCode;
 
-use serde::{self, Serializer};
+use serde::de::DeserializeOwned;
+use serde::{self, Deserialize, Deserializer, Serializer};
 use serde_json;
 
+use mbox;
 use utils;
 
+/// Why a request to Patchwork failed.
+#[derive(Debug)]
+pub enum PatchworkError {
+    /// We couldn't talk to the server, or the connection dropped
+    Transport(reqwest::Error),
+    /// The server responded with an unexpected status
+    Status(StatusCode),
+    /// The server didn't accept our credentials
+    Auth(StatusCode),
+    /// The server's response wasn't what we expected
+    Decode(serde_json::Error),
+    /// A list went on past the most pages we're allowed to fetch
+    Truncated(u64),
+}
+
+impl PatchworkError {
+    /// Whether the same request might work if we try it again.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            PatchworkError::Transport(_) => true,
+            PatchworkError::Status(status) => {
+                status.is_server_error() || status == StatusCode::TooManyRequests
+            }
+            PatchworkError::Auth(_) | PatchworkError::Decode(_) | PatchworkError::Truncated(_) => {
+                false
+            }
+        }
+    }
+}
+
+impl fmt::Display for PatchworkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatchworkError::Transport(ref err) => write!(f, "couldn't reach Patchwork: {}", err),
+            PatchworkError::Status(status) => write!(f, "Patchwork returned {}", status),
+            PatchworkError::Auth(status) => {
+                write!(f, "Patchwork authentication failed: {}", status)
+            }
+            PatchworkError::Decode(ref err) => {
+                write!(f, "couldn't parse Patchwork response: {}", err)
+            }
+            PatchworkError::Truncated(max_pages) => {
+                write!(f, "list goes on past max_pages ({} pages)", max_pages)
+            }
+        }
+    }
+}
+
+impl error::Error for PatchworkError {
+    fn description(&self) -> &str {
+        match *self {
+            PatchworkError::Transport(_) => "couldn't reach Patchwork",
+            PatchworkError::Status(_) => "unexpected status from Patchwork",
+            PatchworkError::Auth(_) => "Patchwork authentication failed",
+            PatchworkError::Decode(_) => "couldn't parse Patchwork response",
+            PatchworkError::Truncated(_) => "Patchwork list too long",
+        }
+    }
+}
+
+impl From<reqwest::Error> for PatchworkError {
+    fn from(err: reqwest::Error) -> PatchworkError {
+        PatchworkError::Transport(err)
+    }
+}
+
+impl From<serde_json::Error> for PatchworkError {
+    fn from(err: serde_json::Error) -> PatchworkError {
+        PatchworkError::Decode(err)
+    }
+}
+
+/// How hard to try idempotent requests again when they fail for reasons
+/// that might go away by themselves.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before the given retry (starting from 0).  This
+    /// doubles each time up to `max_delay`, with up to half of it randomised
+    /// so that clients don't all come back at once.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry).unwrap_or(u32::max_value());
+        let delay = self
+            .initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| cmp::min(delay, self.max_delay));
+        let millis = delay.as_secs() * 1000 + u64::from(delay.subsec_nanos() / 1_000_000);
+        let jitter = if millis > 1 {
+            rand::thread_rng().gen_range(0, millis / 2)
+        } else {
+            0
+        };
+        Duration::from_millis(millis - jitter)
+    }
+}
+
 // TODO: more constants.  constants for format strings of URLs and such.
-pub static PATCHWORK_API: &'static str = "/api/1.0";
+pub static PATCHWORK_API: &'static str = "/api";
+// API versions we understand, newest first.  Fields added after 1.0 are
+// optional in the models below, so they work with any of these.
+pub static PATCHWORK_API_VERSIONS: &'static [&'static str] = &["1.2", "1.1", "1.0"];
 pub static PATCHWORK_QUERY: &'static str = "?order=-id";
 
+// The API versions a server's index at /api/ says it has: either a list of
+// them, or the versions in the URLs of the endpoints it links to
+fn advertised_api_versions(index: &serde_json::Value) -> Vec<String> {
+    let listed = index
+        .get("versions")
+        .and_then(serde_json::Value::as_array)
+        .map_or(vec![], |versions| {
+            versions
+                .iter()
+                .filter_map(|version| version.as_str())
+                .collect()
+        });
+    let linked = index.as_object().map_or(vec![], |endpoints| {
+        endpoints
+            .values()
+            .filter_map(|url| url.as_str())
+            .filter_map(|url| url.find("/api/").map(|start| &url[start + 5..]))
+            .filter_map(|path| path.split('/').next())
+            .filter(|version| {
+                version.contains('.') && version.chars().all(|c| c.is_ascii_digit() || c == '.')
+            })
+            .collect()
+    });
+    let mut versions: Vec<String> = Vec::new();
+    for version in listed.into_iter().chain(linked) {
+        if !versions.iter().any(|known| known == version) {
+            versions.push(version.to_string());
+        }
+    }
+    versions
+}
+
 #[derive(Deserialize, Clone)]
 pub struct SubmitterSummary {
     pub id: u64,
@@ -47,6 +206,17 @@ pub struct SubmitterSummary {
     pub email: String,
 }
 
+// Maintainers are only listed from API 1.1
+#[derive(Deserialize, Clone)]
+pub struct UserSummary {
+    pub id: u64,
+    pub url: String,
+    pub username: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+}
+
 #[derive(Deserialize, Clone)]
 pub struct DelegateSummary {
     pub id: u64,
@@ -68,6 +238,83 @@ pub struct Project {
     pub web_url: Option<String>,
     pub scm_url: Option<String>,
     pub webscm_url: Option<String>,
+    // API 1.1
+    pub subject_match: Option<String>,
+    #[serde(default)]
+    pub maintainers: Vec<UserSummary>,
+    // API 1.2
+    pub list_archive_url: Option<String>,
+}
+
+/// The state of a patch in Patchwork.
+///
+/// Patchwork lets administrators define their own states, so anything we
+/// don't know about ends up in `Other`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum PatchState {
+    New,
+    UnderReview,
+    Accepted,
+    Rejected,
+    Rfc,
+    NotApplicable,
+    ChangesRequested,
+    AwaitingUpstream,
+    Superseded,
+    Deferred,
+    Other(String),
+}
+
+impl PatchState {
+    pub fn as_str(&self) -> &str {
+        match *self {
+            PatchState::New => "new",
+            PatchState::UnderReview => "under-review",
+            PatchState::Accepted => "accepted",
+            PatchState::Rejected => "rejected",
+            PatchState::Rfc => "rfc",
+            PatchState::NotApplicable => "not-applicable",
+            PatchState::ChangesRequested => "changes-requested",
+            PatchState::AwaitingUpstream => "awaiting-upstream",
+            PatchState::Superseded => "superseded",
+            PatchState::Deferred => "deferred",
+            PatchState::Other(ref state) => state,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for PatchState {
+    fn from(state: &str) -> PatchState {
+        match state {
+            "new" => PatchState::New,
+            "under-review" => PatchState::UnderReview,
+            "accepted" => PatchState::Accepted,
+            "rejected" => PatchState::Rejected,
+            "rfc" => PatchState::Rfc,
+            "not-applicable" => PatchState::NotApplicable,
+            "changes-requested" => PatchState::ChangesRequested,
+            "awaiting-upstream" => PatchState::AwaitingUpstream,
+            "superseded" => PatchState::Superseded,
+            "deferred" => PatchState::Deferred,
+            other => PatchState::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for PatchState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for PatchState {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let state = String::deserialize(deserializer)?;
+        Ok(PatchState::from(state.as_str()))
+    }
 }
 
 // /api/1.0/patches/
@@ -83,26 +330,28 @@ pub struct Patch {
     pub name: String,
     pub commit_ref: Option<String>,
     pub pull_url: Option<String>,
-    pub state: String, // TODO enum of possible states
+    pub state: PatchState,
     pub archived: bool,
     pub hash: Option<String>,
     pub submitter: SubmitterSummary,
     pub delegate: Option<DelegateSummary>,
     pub mbox: String,
     pub series: Vec<SeriesSummary>,
-    pub check: String, // TODO enum of possible states
+    pub check: TestState,
     pub checks: String,
     pub tags: BTreeMap<String, u64>,
+    // API 1.1
+    pub comments: Option<String>,
+    // API 1.2
+    pub list_archive_url: Option<String>,
+    #[serde(default)]
+    pub related: Vec<PatchSummary>,
 }
 
 impl Patch {
     pub fn has_series(&self) -> bool {
         !&self.series.is_empty()
     }
-
-    pub fn action_required(&self) -> bool {
-        &self.state == "new" || &self.state == "under-review"
-    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -119,6 +368,7 @@ pub struct PatchSummary {
 pub struct CoverLetter {
     pub date: String,
     pub id: u64,
+    pub mbox: Option<String>,
     pub msgid: String,
     pub name: String,
     pub url: String,
@@ -128,6 +378,8 @@ pub struct CoverLetter {
 // The series list and /series/{id} are the same, luckily
 #[derive(Deserialize, Clone)]
 pub struct Series {
+    // The commit the series is based on, where Patchwork records it
+    pub base: Option<String>,
     pub cover_letter: Option<CoverLetter>,
     pub date: String,
     pub id: u64,
@@ -143,6 +395,15 @@ pub struct Series {
     pub version: u64,
 }
 
+// The base Patchwork recorded for a series, or the one named in the mbox of
+// its cover letter
+fn stated_base(series: &Series, cover_letter: Option<&str>) -> Option<String> {
+    series
+        .base
+        .clone()
+        .or_else(|| cover_letter.and_then(mbox::base_commit).map(str::to_string))
+}
+
 #[derive(Deserialize, Clone)]
 pub struct SeriesSummary {
     pub id: u64,
@@ -153,16 +414,60 @@ pub struct SeriesSummary {
     pub mbox: String,
 }
 
-#[derive(Serialize, Clone, PartialEq)]
+// /api/1.x/events/
+// Only the payload fields for the event categories we act on are modelled.
+#[derive(Deserialize, Clone)]
+pub struct Event {
+    pub id: u64,
+    pub category: String,
+    pub project: Project,
+    pub date: String,
+    pub payload: EventPayload,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct EventPayload {
+    pub patch: Option<PatchSummary>,
+    pub series: Option<SeriesSummary>,
+    pub previous_state: Option<String>,
+    pub current_state: Option<String>,
+}
+
+/// The state of a check, also used for the overall check state of a patch.
+///
+/// Patchwork instances can add their own check states, so anything we don't
+/// know about ends up in `Other`.
+#[derive(Clone, PartialEq, Debug)]
 pub enum TestState {
-    #[serde(rename = "pending")]
     Pending,
-    #[serde(rename = "success")]
     Success,
-    #[serde(rename = "warning")]
     Warning,
-    #[serde(rename = "fail")]
     Fail,
+    Other(String),
+}
+
+impl TestState {
+    pub fn as_str(&self) -> &str {
+        match *self {
+            TestState::Pending => "pending",
+            TestState::Success => "success",
+            TestState::Warning => "warning",
+            TestState::Fail => "fail",
+            TestState::Other(ref state) => state,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for TestState {
+    fn from(state: &str) -> TestState {
+        match state {
+            "pending" => TestState::Pending,
+            "success" => TestState::Success,
+            "warning" => TestState::Warning,
+            "fail" => TestState::Fail,
+            other => TestState::Other(other.to_string()),
+        }
+    }
 }
 
 impl Default for TestState {
@@ -171,8 +476,33 @@ impl Default for TestState {
     }
 }
 
+impl fmt::Display for TestState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl serde::Serialize for TestState {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for TestState {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let state = String::deserialize(deserializer)?;
+        Ok(TestState::from(state.as_str()))
+    }
+}
+
 // /api/1.0/series/*/revisions/*/test-results/
-#[derive(Serialize, Default, Clone)]
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct TestResult {
     pub state: TestState,
     pub target_url: Option<String>,
@@ -201,10 +531,205 @@ impl TestResult {
     }
 }
 
+// /api/1.0/patches/{id}/checks/
+#[allow(dead_code)]
+#[derive(Deserialize, Clone)]
+pub struct Check {
+    pub id: u64,
+    pub url: String,
+    pub date: String,
+    pub state: TestState,
+    pub target_url: Option<String>,
+    pub context: String,
+    pub description: Option<String>,
+}
+
+/// The newest item processed from a Patchwork list, so later polls only need
+/// to ask for items from that date onwards.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct HighWaterMark {
+    pub date: Option<String>,
+    // Patchwork's since= filter is inclusive, so remember which items at
+    // exactly `date` we've already dealt with.
+    pub seen: Vec<u64>,
+}
+
+impl HighWaterMark {
+    /// The date to list items from: the mark, less `lookback` seconds to
+    /// pick up items dated before the mark that arrived after it was set.
+    pub fn since(&self, lookback: u64) -> Option<String> {
+        self.date
+            .as_ref()
+            .map(|date| match utils::parse_iso_date(date) {
+                Some(time) => utils::iso_date(time.saturating_sub(lookback)),
+                None => date.clone(),
+            })
+    }
+
+    pub fn contains(&self, date: &str, id: u64) -> bool {
+        match self.date {
+            Some(ref mark) => date < mark.as_str() || (date == mark && self.seen.contains(&id)),
+            None => false,
+        }
+    }
+
+    /// Move the mark forward past the `processed` items, but never past the
+    /// oldest `deferred` item, so that it is listed again on the next poll.
+    pub fn advance(&mut self, processed: &[(String, u64)], deferred: &[(String, u64)]) {
+        let limit = deferred.iter().map(|&(ref date, _)| date).min();
+        for &(ref date, id) in processed {
+            if deferred.iter().any(|&(_, deferred_id)| deferred_id == id) {
+                continue;
+            }
+            if limit.map_or(false, |limit| date > limit) {
+                continue;
+            }
+            let newer = self.date.as_ref().map_or(true, |mark| date > mark);
+            if newer {
+                self.date = Some(date.clone());
+                self.seen = vec![id];
+            } else if self.date.as_ref() == Some(date) && !self.seen.contains(&id) {
+                self.seen.push(id);
+            }
+        }
+        if let Some(limit) = limit {
+            if self.date.as_ref().map_or(true, |mark| mark < limit) {
+                self.date = Some(limit.clone());
+                self.seen.clear();
+            }
+        }
+    }
+
+    /// As for `advance`, after going through a list that's newest first.  If
+    /// we didn't get to the end of it, there are older items we haven't seen
+    /// yet, so the mark stays where it is.
+    pub fn advance_newest_first(
+        &mut self,
+        listed_all: bool,
+        processed: &[(String, u64)],
+        deferred: &[(String, u64)],
+    ) {
+        if listed_all {
+            self.advance(processed, deferred);
+        }
+    }
+}
+
+// Polling state persisted across restarts, keyed by project link name
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PollState {
+    pub patches: BTreeMap<String, HighWaterMark>,
+    #[serde(default)]
+    pub events: BTreeMap<String, HighWaterMark>,
+}
+
+/// Iterator over a paginated Patchwork API list.
+///
+/// Only one page of results is held in memory at a time.  The next page is
+/// fetched when the current one is exhausted, by following the `Link:
+/// rel="next"` header of the previous response.  If there are more pages
+/// than `max_pages`, the last item is a `PatchworkError::Truncated`.
+pub struct PatchworkPages<'a, T> {
+    server: &'a PatchworkServer,
+    next_url: Option<String>,
+    page: vec::IntoIter<T>,
+    pages_fetched: u64,
+}
+
+impl<'a, T> PatchworkPages<'a, T> {
+    fn new(server: &'a PatchworkServer, url: String) -> PatchworkPages<'a, T> {
+        PatchworkPages {
+            server: server,
+            next_url: Some(url),
+            page: Vec::new().into_iter(),
+            pages_fetched: 0,
+        }
+    }
+}
+
+impl<'a, T> Iterator for PatchworkPages<'a, T>
+where
+    T: DeserializeOwned,
+{
+    type Item = Result<T, PatchworkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.page.next() {
+                return Some(Ok(item));
+            }
+
+            let url = self.next_url.take()?;
+            if let Some(max_pages) = self.server.max_pages {
+                // Say so, since a caller keeping track of what it's seen
+                // needs to know it hasn't seen everything
+                if self.pages_fetched >= max_pages {
+                    debug!(
+                        "Reached maximum of {} pages, not fetching {}",
+                        max_pages, url
+                    );
+                    return Some(Err(PatchworkError::Truncated(max_pages)));
+                }
+            }
+
+            debug!("Fetching page {}", url);
+            let mut resp = match self.server.get_url(&url) {
+                Ok(resp) => resp,
+                Err(err) => return Some(Err(err)),
+            };
+            let next_url = next_page_url(resp.headers());
+            self.pages_fetched += 1;
+
+            // Don't carry on past a page we couldn't read or understand
+            let items = resp
+                .text()
+                .map_err(PatchworkError::from)
+                .and_then(|body| serde_json::from_str::<Vec<T>>(&body).map_err(From::from));
+            match items {
+                Ok(items) => {
+                    self.page = items.into_iter();
+                    self.next_url = next_url;
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+// Patchwork's since= filter is inclusive, and its before= filter exclusive
+fn push_date_range(url: &mut String, since: Option<&str>, before: Option<&str>) {
+    for &(filter, date) in &[("since", since), ("before", before)] {
+        if let Some(date) = date {
+            url.push_str(&format!("&{}=", filter));
+            url.extend(form_urlencoded::byte_serialize(date.as_bytes()));
+        }
+    }
+}
+
+fn next_page_url(headers: &Headers) -> Option<String> {
+    headers.get::<Link>().and_then(|link| {
+        link.values()
+            .iter()
+            .find(|value| {
+                value
+                    .rel()
+                    .map_or(false, |rel| rel.contains(&RelationType::Next))
+            })
+            .map(|value| value.link().to_string())
+    })
+}
+
+#[derive(Clone)]
 pub struct PatchworkServer {
     pub url: String,
     headers: Headers,
     pub client: std::sync::Arc<Client>,
+    api_version: String,
+    retry_policy: RetryPolicy,
+    page_size: Option<u64>,
+    max_pages: Option<u64>,
+    state: PollState,
+    state_file: Option<PathBuf>,
 }
 
 impl PatchworkServer {
@@ -217,7 +742,118 @@ impl PatchworkServer {
             url: url.clone(),
             client: client.clone(),
             headers: headers,
+            api_version: PATCHWORK_API_VERSIONS.last().unwrap().to_string(),
+            retry_policy: RetryPolicy::default(),
+            page_size: None,
+            max_pages: None,
+            state: PollState::default(),
+            state_file: None,
+        }
+    }
+
+    /// Load the polling state from `path`, and save it back there whenever
+    /// it changes.  A missing file is treated as a fresh start.
+    pub fn load_state(&mut self, path: &Path) {
+        if path.is_file() {
+            let file = File::open(path)
+                .unwrap_or_else(|err| panic!("Couldn't open state file {:?}: {}", path, err));
+            self.state = serde_json::from_reader(file)
+                .unwrap_or_else(|err| panic!("Couldn't parse state file {:?}: {}", path, err));
+        }
+        self.state_file = Some(path.to_path_buf());
+    }
+
+    fn save_state(&self) {
+        if let Some(ref path) = self.state_file {
+            let result = serde_json::to_vec(&self.state)
+                .map_err(|err| err.to_string())
+                .and_then(|state| {
+                    utils::write_atomically(path, &state).map_err(|err| err.to_string())
+                });
+            if let Err(err) = result {
+                error!("Couldn't save state file {:?}: {}", path, err);
+            }
+        }
+    }
+
+    pub fn patch_mark(&self, project: &str) -> HighWaterMark {
+        self.state.patches.get(project).cloned().unwrap_or_default()
+    }
+
+    pub fn set_patch_mark(&mut self, project: &str, mark: HighWaterMark) {
+        self.state.patches.insert(project.to_string(), mark);
+        self.save_state();
+    }
+
+    pub fn event_mark(&self, project: &str) -> HighWaterMark {
+        self.state.events.get(project).cloned().unwrap_or_default()
+    }
+
+    pub fn set_event_mark(&mut self, project: &str, mark: HighWaterMark) {
+        self.state.events.insert(project.to_string(), mark);
+        self.save_state();
+    }
+
+    /// Find the newest API version supported by both us and the server, and
+    /// use it from now on.  We go by the versions the server's API index
+    /// says it has, or if it doesn't say, try each version we know about.
+    /// If the server doesn't answer for any of them, we stick with 1.0.
+    pub fn negotiate_api_version(&mut self) -> &str {
+        let index_url = format!("{}{}/", &self.url, PATCHWORK_API);
+        let advertised = match self.get_json::<serde_json::Value>(&index_url) {
+            Ok(index) => advertised_api_versions(&index),
+            Err(err) => {
+                warn!("Couldn't get the API index: {}", err);
+                vec![]
+            }
+        };
+        if let Some(version) = PATCHWORK_API_VERSIONS
+            .iter()
+            .find(|&&version| advertised.iter().any(|advertised| advertised == version))
+        {
+            self.api_version = version.to_string();
+            return &self.api_version;
         }
+
+        debug!("API index doesn't list a version we know, trying each of them");
+        for version in PATCHWORK_API_VERSIONS {
+            let url = format!("{}{}/{}/", &self.url, PATCHWORK_API, version);
+            match self.get_url(&url) {
+                Ok(_) => {
+                    self.api_version = version.to_string();
+                    break;
+                }
+                Err(PatchworkError::Status(status)) => {
+                    debug!("API version {} not supported: {}", version, status)
+                }
+                Err(err) => warn!("Couldn't check for API version {}: {}", version, err),
+            }
+        }
+        &self.api_version
+    }
+
+    fn api_url(&self) -> String {
+        format!("{}{}/{}", &self.url, PATCHWORK_API, &self.api_version)
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn set_pagination(&mut self, page_size: Option<u64>, max_pages: Option<u64>) {
+        self.page_size = page_size;
+        self.max_pages = max_pages;
+    }
+
+    fn paginate<T: DeserializeOwned>(&self, url: &str) -> PatchworkPages<T> {
+        let url = match self.page_size {
+            Some(page_size) => {
+                let separator = if url.contains('?') { '&' } else { '?' };
+                format!("{}{}per_page={}", url, separator, page_size)
+            }
+            None => url.to_string(),
+        };
+        PatchworkPages::new(self, url)
     }
 
     #[cfg_attr(feature = "cargo-clippy", allow(ptr_arg))]
@@ -241,108 +877,182 @@ impl PatchworkServer {
         }
     }
 
-    pub fn get_url(&self, url: &str) -> std::result::Result<Response, reqwest::Error> {
-        self.client
-            .get(&*url)
-            .headers(self.headers.clone())
-            .header(Connection::close())
-            .send()
+    // Turn an error status into the right kind of error
+    fn check_status(resp: Response) -> Result<Response, PatchworkError> {
+        let status = resp.status();
+        if status == StatusCode::Unauthorized || status == StatusCode::Forbidden {
+            Err(PatchworkError::Auth(status))
+        } else if !status.is_success() {
+            Err(PatchworkError::Status(status))
+        } else {
+            Ok(resp)
+        }
     }
 
-    pub fn get_url_string(&self, url: &str) -> std::result::Result<String, reqwest::Error> {
-        let mut resp = try!(
-            self.client
+    /// GET a URL, retrying according to the retry policy if it fails in a
+    /// way that might be temporary.
+    pub fn get_url(&self, url: &str) -> Result<Response, PatchworkError> {
+        let mut retry = 0;
+        loop {
+            let result = self
+                .client
                 .get(&*url)
                 .headers(self.headers.clone())
                 .header(Connection::close())
                 .send()
-        );
-        let mut body: Vec<u8> = vec![];
-        io::copy(&mut resp, &mut body).unwrap();
-        Ok(String::from_utf8(body).unwrap())
+                .map_err(PatchworkError::from)
+                .and_then(PatchworkServer::check_status);
+            match result {
+                Err(ref err) if err.is_transient() && retry < self.retry_policy.max_retries => {
+                    let delay = self.retry_policy.delay(retry);
+                    warn!(
+                        "Request to {} failed ({}), retrying in {}s",
+                        url,
+                        err,
+                        delay.as_secs()
+                    );
+                    thread::sleep(delay);
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    pub fn get_url_string(&self, url: &str) -> Result<String, PatchworkError> {
+        let mut resp = self.get_url(url)?;
+        Ok(resp.text()?)
+    }
+
+    fn get_json<T: DeserializeOwned>(&self, url: &str) -> Result<T, PatchworkError> {
+        Ok(serde_json::from_str(&self.get_url_string(url)?)?)
     }
 
+    // Not retried, since we can't tell whether a failed POST took effect
     pub fn post_test_result(
         &self,
         result: TestResult,
         checks_url: &str,
-    ) -> Result<StatusCode, reqwest::Error> {
-        let encoded = serde_json::to_string(&result).unwrap();
+    ) -> Result<StatusCode, PatchworkError> {
+        let encoded = serde_json::to_string(&result)?;
         let headers = self.headers.clone();
         debug!("JSON Encoded: {}", encoded);
-        let mut resp = try!(
-            self.client
-                .post(checks_url)
-                .headers(headers)
-                .body(encoded)
-                .send()
-        );
-        let mut body: Vec<u8> = vec![];
-        io::copy(&mut resp, &mut body).unwrap();
-        trace!("{}", String::from_utf8(body).unwrap());
-        assert_eq!(resp.status(), StatusCode::Created);
+        let resp = self
+            .client
+            .post(checks_url)
+            .headers(headers)
+            .body(encoded)
+            .send()?;
+        let mut resp = PatchworkServer::check_status(resp)?;
+        trace!("{}", resp.text()?);
+        if resp.status() != StatusCode::Created {
+            return Err(PatchworkError::Status(resp.status()));
+        }
         Ok(resp.status())
     }
 
-    pub fn get_project(&self, url: &str) -> Result<Project, serde_json::Error> {
-        serde_json::from_str(&self.get_url_string(url).unwrap())
+    pub fn get_project(&self, url: &str) -> Result<Project, PatchworkError> {
+        self.get_json(url)
     }
 
-    pub fn get_patch(&self, patch_id: &u64) -> Result<Patch, serde_json::Error> {
-        let url = format!(
-            "{}{}/patches/{}{}",
-            &self.url, PATCHWORK_API, patch_id, PATCHWORK_QUERY
+    pub fn get_patch(&self, patch_id: &u64) -> Result<Patch, PatchworkError> {
+        let url = format!("{}/patches/{}{}", self.api_url(), patch_id, PATCHWORK_QUERY);
+        self.get_json(&url)
+    }
+
+    pub fn get_patch_by_url(&self, url: &str) -> Result<Patch, PatchworkError> {
+        self.get_json(url)
+    }
+
+    /// List a project's patches dated from `since` up to but not including
+    /// `before`, newest first.
+    pub fn get_patch_query(
+        &self,
+        project: &str,
+        since: Option<&str>,
+        before: Option<&str>,
+    ) -> PatchworkPages<Patch> {
+        let mut url = format!(
+            "{}/patches/{}&project={}",
+            self.api_url(),
+            PATCHWORK_QUERY,
+            project
         );
-        serde_json::from_str(&self.get_url_string(&url).unwrap())
+        push_date_range(&mut url, since, before);
+        self.paginate(&url)
     }
 
-    pub fn get_patch_by_url(&self, url: &str) -> Result<Patch, serde_json::Error> {
-        serde_json::from_str(&self.get_url_string(url).unwrap())
+    /// Whether the server has the event stream introduced in Patchwork 2.0.
+    pub fn supports_events(&self) -> bool {
+        let url = format!("{}/events/", self.api_url());
+        match self.get_url(&url) {
+            Ok(_) => true,
+            Err(PatchworkError::Status(_)) => false,
+            Err(err) => {
+                warn!("Couldn't check for Patchwork events support: {}", err);
+                false
+            }
+        }
     }
 
-    pub fn get_patch_query(&self, project: &str) -> Result<Vec<Patch>, serde_json::Error> {
+    // Events are listed oldest first, so they're handled in the order they
+    // happened.
+    pub fn get_events_query(
+        &self,
+        project: &str,
+        since: Option<&str>,
+        before: Option<&str>,
+    ) -> PatchworkPages<Event> {
+        let mut url = format!("{}/events/?order=date&project={}", self.api_url(), project);
+        push_date_range(&mut url, since, before);
+        self.paginate(&url)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_series_query(&self, project: &str) -> PatchworkPages<Series> {
         let url = format!(
-            "{}{}/patches/{}&project={}",
-            &self.url, PATCHWORK_API, PATCHWORK_QUERY, project
+            "{}/series/{}&project={}",
+            self.api_url(),
+            PATCHWORK_QUERY,
+            project
         );
+        self.paginate(&url)
+    }
 
-        serde_json::from_str(&self.get_url_string(&url)
-            .unwrap_or_else(|err| panic!("Failed to connect to Patchwork: {}", err)))
+    #[allow(dead_code)]
+    pub fn get_checks_query(&self, patch: &Patch) -> PatchworkPages<Check> {
+        self.paginate(&patch.checks)
     }
 
-    pub fn get_patch_dependencies(&self, patch: &Patch) -> Vec<Patch> {
+    pub fn get_patch_dependencies(&self, patch: &Patch) -> Result<Vec<Patch>, PatchworkError> {
         // We assume the list of patches in a series are in order.
         let mut dependencies: Vec<Patch> = vec![];
-        let series = self.get_series_by_url(&patch.series[0].url);
-        if series.is_err() {
-            return dependencies;
-        }
-        for dependency in series.unwrap().patches {
-            dependencies.push(self.get_patch_by_url(&dependency.url).unwrap());
+        let series = self.get_series_by_url(&patch.series[0].url)?;
+        for dependency in series.patches {
+            dependencies.push(self.get_patch_by_url(&dependency.url)?);
             if dependency.url == patch.url {
                 break;
             }
         }
-        dependencies
+        Ok(dependencies)
     }
 
-    pub fn get_patch_mbox(&self, patch: &Patch) -> PathBuf {
+    pub fn get_patch_mbox(&self, patch: &Patch) -> Result<PathBuf, PatchworkError> {
         let dir = TempDir::new("snowpatch").unwrap().into_path();
         let mut path = dir.clone();
         let tag = utils::sanitise_path(patch.name.clone());
         path.push(format!("{}.mbox", tag));
 
-        let mut mbox_resp = self.get_url(&patch.mbox).unwrap();
+        let mut mbox_resp = self.get_url(&patch.mbox)?;
 
         debug!("Saving patch to file {}", path.display());
         let mut mbox =
             File::create(&path).unwrap_or_else(|err| panic!("Couldn't create mbox file: {}", err));
-        io::copy(&mut mbox_resp, &mut mbox)
-            .unwrap_or_else(|err| panic!("Couldn't save mbox from Patchwork: {}", err));
-        path
+        mbox_resp.copy_to(&mut mbox)?;
+        Ok(path)
     }
 
-    pub fn get_patches_mbox(&self, patches: Vec<Patch>) -> PathBuf {
+    pub fn get_patches_mbox(&self, patches: Vec<Patch>) -> Result<PathBuf, PatchworkError> {
         let dir = TempDir::new("snowpatch").unwrap().into_path();
         let mut path = dir.clone();
         let tag = utils::sanitise_path(patches.last().unwrap().name.clone());
@@ -356,23 +1066,352 @@ impl PatchworkServer {
             .unwrap_or_else(|err| panic!("Couldn't make file: {}", err));
 
         for patch in patches {
-            let mut mbox_resp = self.get_url(&patch.mbox).unwrap();
+            let mut mbox_resp = self.get_url(&patch.mbox)?;
             debug!("Appending patch {} to file {}", patch.name, path.display());
-            io::copy(&mut mbox_resp, &mut mbox)
-                .unwrap_or_else(|err| panic!("Couldn't save mbox from Patchwork: {}", err));
+            mbox_resp.copy_to(&mut mbox)?;
         }
-        path
+        Ok(path)
     }
 
-    pub fn get_series(&self, series_id: &u64) -> Result<Series, serde_json::Error> {
-        let url = format!(
-            "{}{}/series/{}{}",
-            &self.url, PATCHWORK_API, series_id, PATCHWORK_QUERY
+    pub fn get_series(&self, series_id: &u64) -> Result<Series, PatchworkError> {
+        let url = format!("{}/series/{}{}", self.api_url(), series_id, PATCHWORK_QUERY);
+        self.get_json(&url)
+    }
+
+    pub fn get_series_by_url(&self, url: &str) -> Result<Series, PatchworkError> {
+        self.get_json(url)
+    }
+
+    /// The commit a series says it's based on, if it says.  Patchwork may
+    /// have recorded it, or "git format-patch --base" puts it in the cover
+    /// letter.
+    pub fn get_series_base(&self, series: &Series) -> Option<String> {
+        let cover_letter = match series.cover_letter {
+            Some(ref cover_letter) if series.base.is_none() => cover_letter,
+            _ => return stated_base(series, None),
+        };
+        let mbox = cover_letter
+            .mbox
+            .as_ref()
+            .and_then(|url| match self.get_url_string(url) {
+                Ok(mbox) => Some(mbox),
+                Err(err) => {
+                    warn!("Couldn't get cover letter {}: {}", cover_letter.name, err);
+                    None
+                }
+            });
+        stated_base(series, mbox.as_ref().map(String::as_str))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use patchwork::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn retry_delay_backs_off() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(10),
+        };
+        for &(retry, max) in &[(0, 2), (1, 4), (2, 8), (3, 10), (20, 10), (40, 10)] {
+            let delay = policy.delay(retry);
+            assert!(delay <= Duration::from_secs(max));
+            assert!(delay >= Duration::from_secs(max) / 2);
+        }
+    }
+
+    #[test]
+    fn patch_state_from_str() {
+        assert_eq!(PatchState::from("new"), PatchState::New);
+        assert_eq!(PatchState::from("under-review"), PatchState::UnderReview);
+        assert_eq!(
+            PatchState::from("awaiting-upstream"),
+            PatchState::AwaitingUpstream
+        );
+        assert_eq!(
+            PatchState::from("needs-ack"),
+            PatchState::Other("needs-ack".to_string())
+        );
+        assert_eq!(
+            PatchState::from("changes-requested").as_str(),
+            "changes-requested"
+        );
+        assert_eq!(
+            PatchState::Other("needs-ack".to_string()).as_str(),
+            "needs-ack"
+        );
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let check: Check = serde_json::from_str(
+            r#"{"id": 1, "url": "https://patchwork/api/checks/1/",
+                "date": "2018-07-11T04:21:05", "state": "needs-review",
+                "target_url": null, "context": "ci", "description": null}"#,
+        )
+        .unwrap();
+        assert_eq!(check.state, TestState::Other("needs-review".to_string()));
+        assert_eq!(TestState::from("fail"), TestState::Fail);
+        assert_eq!(
+            serde_json::to_string(&TestState::Warning).unwrap(),
+            "\"warning\""
+        );
+        assert_eq!(
+            serde_json::to_string(&check.state).unwrap(),
+            "\"needs-review\""
+        );
+    }
+
+    #[test]
+    fn api_versions_from_index() {
+        let index: serde_json::Value = serde_json::from_str(
+            r#"{"projects": "https://patchwork/api/1.1/projects/",
+                "patches": "https://patchwork/api/1.1/patches/",
+                "series": "https://patchwork/api/1.1/series/"}"#,
+        )
+        .unwrap();
+        assert_eq!(advertised_api_versions(&index), vec!["1.1"]);
+        let index: serde_json::Value = serde_json::from_str(
+            r#"{"versions": ["1.0", "1.2", "2.0"],
+                "projects": "https://patchwork/api/1.2/projects/"}"#,
+        )
+        .unwrap();
+        assert_eq!(advertised_api_versions(&index), vec!["1.0", "1.2", "2.0"]);
+        // Unversioned URLs don't tell us anything
+        let index: serde_json::Value =
+            serde_json::from_str(r#"{"projects": "https://patchwork/api/projects/"}"#).unwrap();
+        assert!(advertised_api_versions(&index).is_empty());
+    }
+
+    fn series(base: Option<&str>) -> Series {
+        let json = r#"{
+                "id": 56, "url": "https://patchwork/api/series/56/",
+                "date": "2018-07-11T04:21:05", "mbox": "https://patchwork/series/56/mbox/",
+                "name": "Say hello", "version": 1, "total": 1, "received_total": 1,
+                "received_all": true, "patches": [],
+                "project": {
+                    "id": 1, "url": "https://patchwork/api/projects/1/", "name": "linux",
+                    "link_name": "linux", "list_id": "linux.example.com",
+                    "list_email": "linux@example.com", "web_url": null, "scm_url": null,
+                    "webscm_url": null
+                },
+                "submitter": {
+                    "id": 1, "url": "https://patchwork/api/people/1/",
+                    "name": "Andrew Donnellan", "email": "andrew@example.com"
+                },
+                "cover_letter": {
+                    "id": 7, "url": "https://patchwork/api/covers/7/",
+                    "date": "2018-07-11T04:21:05", "msgid": "<0@example.com>",
+                    "name": "[PATCH 0/1] Say hello", "mbox": "https://patchwork/cover/7/mbox/"
+                }
+            }"#;
+        let json = match base {
+            Some(base) => json.replacen("{", &format!("{{\"base\": \"{}\",", base), 1),
+            None => json.to_string(),
+        };
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn series_base_from_patchwork() {
+        let series = series(Some("60cc43fc888428bb2f18f08997432d426a243338"));
+        assert_eq!(
+            stated_base(&series, Some("base-commit: 0123abcd\n")),
+            Some("60cc43fc888428bb2f18f08997432d426a243338".to_string())
         );
-        serde_json::from_str(&self.get_url_string(&url).unwrap())
     }
 
-    pub fn get_series_by_url(&self, url: &str) -> Result<Series, serde_json::Error> {
-        serde_json::from_str(&self.get_url_string(url).unwrap())
+    #[test]
+    fn series_base_from_cover_letter() {
+        let series = series(None);
+        let cover_letter = "From patchwork Wed Jul 11 04:21:05 2018\n\
+                            Subject: [PATCH 0/1] Say hello\n\
+                            \n\
+                            Some words about the series.\n\
+                            \n\
+                            base-commit: 60cc43fc888428bb2f18f08997432d426a243338\n";
+        assert_eq!(
+            stated_base(&series, Some(cover_letter)),
+            Some("60cc43fc888428bb2f18f08997432d426a243338".to_string())
+        );
+        assert_eq!(stated_base(&series, None), None);
+    }
+
+    #[test]
+    fn next_page_url_follows_rel_next() {
+        let mut headers = Headers::new();
+        headers.set_raw(
+            "Link",
+            "<https://patchwork.ozlabs.org/api/1.0/patches/?page=3>; rel=\"next\", \
+             <https://patchwork.ozlabs.org/api/1.0/patches/?page=1>; rel=\"prev\"",
+        );
+        assert_eq!(
+            next_page_url(&headers),
+            Some("https://patchwork.ozlabs.org/api/1.0/patches/?page=3".to_string())
+        );
+    }
+
+    fn processed(items: &[(&str, u64)]) -> Vec<(String, u64)> {
+        items
+            .iter()
+            .map(|&(date, id)| (date.to_string(), id))
+            .collect()
+    }
+
+    #[test]
+    fn high_water_mark_advances_to_newest() {
+        let mut mark = HighWaterMark::default();
+        mark.advance(
+            &processed(&[
+                ("2018-07-03T10:00:00", 3),
+                ("2018-07-03T10:00:00", 2),
+                ("2018-07-01T09:00:00", 1),
+            ]),
+            &[],
+        );
+        assert_eq!(mark.date, Some("2018-07-03T10:00:00".to_string()));
+        assert_eq!(mark.seen, vec![3, 2]);
+        assert!(mark.contains("2018-07-03T10:00:00", 2));
+        assert!(mark.contains("2018-07-01T09:00:00", 1));
+        assert!(!mark.contains("2018-07-03T10:00:00", 4));
+        assert!(!mark.contains("2018-07-04T00:00:00", 5));
+    }
+
+    #[test]
+    fn high_water_mark_looks_back() {
+        let mut mark = HighWaterMark::default();
+        assert_eq!(mark.since(3600), None);
+        mark.advance(&processed(&[("2018-07-03T10:00:00", 3)]), &[]);
+        assert_eq!(mark.since(0), Some("2018-07-03T10:00:00".to_string()));
+        assert_eq!(
+            mark.since(86_400 + 3600),
+            Some("2018-07-02T09:00:00".to_string())
+        );
+    }
+
+    #[test]
+    fn high_water_mark_stops_at_deferred() {
+        let mut mark = HighWaterMark::default();
+        mark.advance(
+            &processed(&[
+                ("2018-07-03T10:00:00", 3),
+                ("2018-07-02T10:00:00", 2),
+                ("2018-07-01T09:00:00", 1),
+            ]),
+            &processed(&[("2018-07-02T10:00:00", 2)]),
+        );
+        assert_eq!(mark.date, Some("2018-07-02T10:00:00".to_string()));
+        assert!(mark.seen.is_empty());
+        assert!(!mark.contains("2018-07-02T10:00:00", 2));
+        assert!(!mark.contains("2018-07-03T10:00:00", 3));
+    }
+
+    #[test]
+    fn patch_queries_cover_a_date_range() {
+        let client = std::sync::Arc::new(Client::new());
+        let server = PatchworkServer::new(&"https://patchwork".to_string(), &client);
+        let query = server.get_patch_query("linux", Some("2018-07-11T04:21:05"), None);
+        assert_eq!(
+            query.next_url.unwrap(),
+            "https://patchwork/api/1.0/patches/?order=-id&project=linux\
+             &since=2018-07-11T04%3A21%3A05"
+        );
+        let query = server.get_events_query("linux", None, Some("2018-07-12T00:00:00"));
+        assert_eq!(
+            query.next_url.unwrap(),
+            "https://patchwork/api/1.0/events/?order=date&project=linux\
+             &before=2018-07-12T00%3A00%3A00"
+        );
+    }
+
+    // A Patchwork that serves `pages` of a list in turn, each linking to the
+    // next
+    fn fake_patchwork(pages: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let next_url = url.clone();
+        thread::spawn(move || {
+            let count = pages.len();
+            for (page, body) in pages.into_iter().enumerate() {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let len = stream.read(&mut buf).unwrap();
+                    if len == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..len]);
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: {}\r\n",
+                    body.len()
+                )
+                .unwrap();
+                if page + 1 < count {
+                    write!(
+                        stream,
+                        "Link: <{}/api/1.0/patches/?page={}>; rel=\"next\"\r\n",
+                        next_url,
+                        page + 2
+                    )
+                    .unwrap();
+                }
+                write!(stream, "\r\n{}", body).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn truncated_lists_hold_the_mark_back() {
+        let url = fake_patchwork(vec![
+            r#"[{"id": 3, "date": "2018-07-03T10:00:00"}]"#,
+            r#"[{"id": 2, "date": "2018-07-02T10:00:00"}]"#,
+        ]);
+        let mut server = PatchworkServer::new(&url, &std::sync::Arc::new(Client::new()));
+        server.set_pagination(Some(1), Some(1));
+        let mut mark = HighWaterMark::default();
+        mark.advance(&processed(&[("2018-07-01T10:00:00", 1)]), &[]);
+
+        // As the daemon goes through the newest first list of patches
+        let mut processed = Vec::new();
+        let mut listed_all = true;
+        for patch in server.paginate::<serde_json::Value>(&format!("{}/api/1.0/patches/", url)) {
+            match patch {
+                Ok(patch) => processed.push((
+                    patch["date"].as_str().unwrap().to_string(),
+                    patch["id"].as_u64().unwrap(),
+                )),
+                Err(PatchworkError::Truncated(1)) => {
+                    listed_all = false;
+                    break;
+                }
+                Err(err) => panic!("Unexpected error: {}", err),
+            }
+        }
+        assert_eq!(processed.len(), 1);
+        assert!(!listed_all);
+
+        // Patch 2 wasn't listed, so the mark can't move past it
+        mark.advance_newest_first(listed_all, &processed, &[]);
+        assert_eq!(mark.date, Some("2018-07-01T10:00:00".to_string()));
+        assert!(!mark.contains("2018-07-02T10:00:00", 2));
+    }
+
+    #[test]
+    fn next_page_url_last_page() {
+        let mut headers = Headers::new();
+        headers.set_raw(
+            "Link",
+            "<https://patchwork.ozlabs.org/api/1.0/patches/?page=1>; rel=\"prev\"",
+        );
+        assert_eq!(next_page_url(&headers), None);
+        assert_eq!(next_page_url(&Headers::new()), None);
     }
 }
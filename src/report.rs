@@ -0,0 +1,159 @@
+//
+// snowpatch - continuous integration for patch-based workflows
+//
+// Copyright (C) 2018 IBM Corporation
+// Authors:
+//     Russell Currey <ruscur@russell.cc>
+//     Andrew Donnellan <andrew.donnellan@au1.ibm.com>
+//
+// This program is free software; you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation; either version 2 of the License, or (at your option)
+// any later version.
+//
+// report.rs - combine test results into what gets reported to Patchwork
+//
+
+use patchwork::{TestResult, TestState};
+use settings::SeriesChecks;
+
+pub static AGGREGATE_CONTEXT: &'static str = "snowpatch";
+pub static SERIES_CONTEXT_PREFIX: &'static str = "series-";
+
+// How bad a state is, for picking the worst of a set of results
+fn severity(state: &TestState) -> u8 {
+    match *state {
+        TestState::Success => 0,
+        // Treat states we don't know like pending ones
+        TestState::Pending | TestState::Other(_) => 1,
+        TestState::Warning => 2,
+        TestState::Fail => 3,
+    }
+}
+
+fn context_name(result: &TestResult) -> &str {
+    result
+        .context
+        .as_ref()
+        .map_or(AGGREGATE_CONTEXT, String::as_str)
+}
+
+/// Roll up the results of every step of a test run into one overall result,
+/// with the worst state of any of them.
+pub fn aggregate(results: &[TestResult]) -> TestResult {
+    let state = results
+        .iter()
+        .map(|result| &result.state)
+        .max_by_key(|state| severity(state))
+        .cloned()
+        .unwrap_or(TestState::Pending);
+
+    let count = |state: TestState| results.iter().filter(|r| r.state == state).count();
+    let mut description = format!(
+        "{} passed, {} warning, {} failed, {} pending",
+        count(TestState::Success),
+        count(TestState::Warning),
+        count(TestState::Fail),
+        count(TestState::Pending)
+    );
+    for &(ref state, label) in &[(TestState::Fail, "Failed"), (TestState::Warning, "Warning")] {
+        let contexts: Vec<&str> = results
+            .iter()
+            .filter(|r| r.state == *state)
+            .map(context_name)
+            .collect();
+        if !contexts.is_empty() {
+            description.push_str(&format!("\n{}: {}", label, contexts.join(", ")));
+        }
+    }
+
+    TestResult {
+        state: state,
+        description: Some(description),
+        context: Some(AGGREGATE_CONTEXT.to_string()),
+        ..Default::default()
+    }
+}
+
+/// The results from testing a whole series that should also be posted to
+/// every other patch in the series.  Their contexts are prefixed so they
+/// don't clash with the results from testing those patches individually.
+pub fn series_results(results: &[TestResult], series_checks: &SeriesChecks) -> Vec<TestResult> {
+    let selected = match *series_checks {
+        SeriesChecks::None => vec![],
+        SeriesChecks::Aggregate => match results
+            .iter()
+            .find(|r| context_name(r) == AGGREGATE_CONTEXT)
+        {
+            Some(overall) => vec![overall.clone()],
+            None => vec![aggregate(results)],
+        },
+        SeriesChecks::All => results.to_vec(),
+    };
+
+    selected
+        .into_iter()
+        .map(|result| TestResult {
+            context: Some(format!(
+                "{}{}",
+                SERIES_CONTEXT_PREFIX,
+                context_name(&result)
+            )),
+            ..result
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use report::*;
+
+    fn result(state: TestState, context: &str) -> TestResult {
+        TestResult {
+            state: state,
+            context: Some(context.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn aggregate_takes_worst_state() {
+        let results = vec![
+            result(TestState::Success, "apply_patch"),
+            result(TestState::Warning, "checkpatch"),
+            result(TestState::Fail, "build"),
+            result(TestState::Success, "boot"),
+        ];
+        let overall = aggregate(&results);
+        assert!(overall.state == TestState::Fail);
+        assert_eq!(overall.context, Some("snowpatch".to_string()));
+        assert_eq!(
+            overall.description,
+            Some(
+                "2 passed, 1 warning, 1 failed, 0 pending\nFailed: build\nWarning: checkpatch"
+                    .to_string()
+            )
+        );
+
+        let overall = aggregate(&results[..1]);
+        assert!(overall.state == TestState::Success);
+    }
+
+    #[test]
+    fn series_results_prefix_contexts() {
+        let results = vec![
+            result(TestState::Success, "apply_patch"),
+            result(TestState::Fail, "build"),
+        ];
+        assert!(series_results(&results, &SeriesChecks::None).is_empty());
+
+        let aggregated = series_results(&results, &SeriesChecks::Aggregate);
+        assert_eq!(aggregated.len(), 1);
+        assert_eq!(aggregated[0].context, Some("series-snowpatch".to_string()));
+        assert!(aggregated[0].state == TestState::Fail);
+
+        let all = series_results(&results, &SeriesChecks::All);
+        let contexts: Vec<_> = all.iter().map(|r| r.context.clone().unwrap()).collect();
+        assert_eq!(contexts, vec!["series-apply_patch", "series-build"]);
+    }
+}
@@ -0,0 +1,203 @@
+//
+// snowpatch - continuous integration for patch-based workflows
+//
+// Copyright (C) 2018 IBM Corporation
+// Authors:
+//     Russell Currey <ruscur@russell.cc>
+//     Andrew Donnellan <andrew.donnellan@au1.ibm.com>
+//
+// This program is free software; you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation; either version 2 of the License, or (at your option)
+// any later version.
+//
+// scheduler.rs - run several tests at once, within limits
+//
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+#[derive(Default)]
+struct Jobs {
+    running: usize,
+    per_project: BTreeMap<String, usize>,
+    next_ticket: u64,
+    // The oldest job that hasn't finished yet
+    oldest: u64,
+    finished: BTreeSet<u64>,
+}
+
+type Shared = Arc<(Mutex<Jobs>, Condvar)>;
+
+// A panicking job shouldn't stop the others
+fn lock(shared: &Shared) -> MutexGuard<Jobs> {
+    shared.0.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Runs tests on a pool of threads, no more than `max_jobs` at once and no
+/// more than a project's limit at once for that project.
+pub struct Scheduler {
+    max_jobs: usize,
+    project_limits: BTreeMap<String, usize>,
+    jobs: Shared,
+    repo_locks: Mutex<BTreeMap<String, Arc<Mutex<()>>>>,
+}
+
+/// A job's place in the pool.  Jobs are numbered in the order they were
+/// spawned, so they can take turns to do things in that order.
+pub struct Ticket {
+    number: u64,
+    project: String,
+    jobs: Shared,
+}
+
+impl Ticket {
+    /// Wait until every job spawned before this one has finished.
+    pub fn wait_turn(&self) {
+        let mut jobs = lock(&self.jobs);
+        while jobs.oldest != self.number {
+            jobs = self
+                .jobs
+                .1
+                .wait(jobs)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut jobs = lock(&self.jobs);
+        jobs.running -= 1;
+        if let Some(count) = jobs.per_project.get_mut(&self.project) {
+            *count -= 1;
+        }
+        jobs.finished.insert(self.number);
+        loop {
+            let oldest = jobs.oldest;
+            if !jobs.finished.remove(&oldest) {
+                break;
+            }
+            jobs.oldest += 1;
+        }
+        self.jobs.1.notify_all();
+    }
+}
+
+impl Scheduler {
+    pub fn new(max_jobs: usize, project_limits: BTreeMap<String, usize>) -> Scheduler {
+        Scheduler {
+            max_jobs: max_jobs,
+            project_limits: project_limits,
+            jobs: Arc::new((Mutex::new(Jobs::default()), Condvar::new())),
+            repo_locks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn has_room(&self, jobs: &Jobs, project: &str) -> bool {
+        let running = jobs.per_project.get(project).cloned().unwrap_or(0);
+        let limit = self.project_limits.get(project).cloned();
+        jobs.running < self.max_jobs && limit.map_or(true, |limit| running < limit)
+    }
+
+    /// Run a job on its own thread, first waiting until there's room for it.
+    pub fn spawn<F>(&self, project: &str, name: String, job: F)
+    where
+        F: FnOnce(&Ticket) + Send + 'static,
+    {
+        let ticket = {
+            let mut jobs = lock(&self.jobs);
+            while !self.has_room(&jobs, project) {
+                jobs = self
+                    .jobs
+                    .1
+                    .wait(jobs)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            jobs.running += 1;
+            *jobs.per_project.entry(project.to_string()).or_insert(0) += 1;
+            jobs.next_ticket += 1;
+            Ticket {
+                number: jobs.next_ticket - 1,
+                project: project.to_string(),
+                jobs: self.jobs.clone(),
+            }
+        };
+
+        thread::Builder::new()
+            .name(name)
+            .spawn(move || job(&ticket))
+            .unwrap_or_else(|err| panic!("Couldn't start test thread: {}", err));
+    }
+
+    /// Wait for every job to finish.
+    pub fn wait_idle(&self) {
+        let mut jobs = lock(&self.jobs);
+        while jobs.running > 0 {
+            jobs = self
+                .jobs
+                .1
+                .wait(jobs)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// A lock to hold while fetching into a repository, since git can't do
+    /// two fetches into the same repository at once.
+    pub fn repo_lock(&self, repository: &str) -> Arc<Mutex<()>> {
+        self.repo_locks
+            .lock()
+            .unwrap()
+            .entry(repository.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use scheduler::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn jobs_take_turns_in_order() {
+        let scheduler = Scheduler::new(3, BTreeMap::new());
+        let (tx, rx) = mpsc::channel();
+        // Later jobs finish first, but still wait for their turn
+        for i in 0..3 {
+            let tx = tx.clone();
+            scheduler.spawn("linux", format!("job{}", i), move |ticket| {
+                thread::sleep(Duration::from_millis(50 * (3 - i)));
+                ticket.wait_turn();
+                tx.send(i).unwrap();
+            });
+        }
+        scheduler.wait_idle();
+        let order: Vec<u64> = rx.try_iter().collect();
+        assert_eq!(order, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn project_limit_is_respected() {
+        let mut limits = BTreeMap::new();
+        limits.insert("linux".to_string(), 1);
+        let scheduler = Scheduler::new(4, limits);
+        let running = Arc::new(Mutex::new((0, 0)));
+        for i in 0..4 {
+            let running = running.clone();
+            scheduler.spawn("linux", format!("job{}", i), move |_| {
+                {
+                    let mut running = running.lock().unwrap();
+                    running.0 += 1;
+                    running.1 = running.1.max(running.0);
+                }
+                thread::sleep(Duration::from_millis(10));
+                running.lock().unwrap().0 -= 1;
+            });
+        }
+        scheduler.wait_idle();
+        assert_eq!(running.lock().unwrap().1, 1);
+    }
+}
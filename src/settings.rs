@@ -18,7 +18,12 @@ use toml;
 
 use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
 
-use git2::{Error, Repository};
+use git2::{Error, Reference, Repository};
+
+use git::RefNames;
+use patchwork::PatchState;
+
+use regex::Regex;
 
 use std::collections::BTreeMap;
 use std::fmt;
@@ -27,12 +32,93 @@ use std::io::Read;
 
 // TODO: Give more informative error messages when we fail to parse.
 
-#[derive(Deserialize, Clone)]
-pub struct Git {
-    pub user: String,
+// How to authenticate to a git remote
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub enum CredentialMethod {
+    #[serde(rename = "ssh_key")]
+    SshKey,
+    #[serde(rename = "ssh_agent")]
+    SshAgent,
+    #[serde(rename = "userpass")]
+    UserPass,
+    #[serde(rename = "helper")]
+    Helper,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct Credentials {
+    // Which remote URLs these are for, with * matching anything
+    pub url: Option<String>,
+    pub method: Option<CredentialMethod>,
+    pub user: Option<String>,
     pub public_key: Option<String>,
-    pub private_key: String,
+    pub private_key: Option<String>,
     pub passphrase: Option<String>,
+    // A password or access token
+    pub password: Option<String>,
+}
+
+impl Credentials {
+    /// How to authenticate.  If it isn't stated, it's with the SSH key if
+    /// we have one, with the password if we have one, or with ssh-agent.
+    pub fn method(&self) -> CredentialMethod {
+        match self.method {
+            Some(ref method) => method.clone(),
+            None if self.private_key.is_some() => CredentialMethod::SshKey,
+            None if self.password.is_some() => CredentialMethod::UserPass,
+            None => CredentialMethod::SshAgent,
+        }
+    }
+}
+
+// Whether a URL matches a pattern in which * matches anything
+fn url_matches(pattern: &str, url: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !url.starts_with(first) {
+        return false;
+    }
+    let mut rest = &url[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+    let last = match parts.split_last() {
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(at) => rest = &rest[at + part.len()..],
+                    None => return false,
+                }
+            }
+            last
+        }
+        // No wildcards, so it has to be the whole URL
+        None => return rest.is_empty(),
+    };
+    rest.ends_with(last)
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Git {
+    #[serde(flatten)]
+    pub default: Credentials,
+    // Credentials for particular remotes, picked by URL
+    pub credentials: Option<Vec<Credentials>>,
+}
+
+impl Git {
+    /// The credentials for a remote URL: the first `credentials` whose
+    /// pattern matches it, or the defaults.
+    pub fn credentials_for(&self, url: &str) -> &Credentials {
+        self.credentials
+            .iter()
+            .flat_map(|credentials| credentials.iter())
+            .find(|credentials| {
+                credentials
+                    .url
+                    .as_ref()
+                    .map_or(false, |pattern| url_matches(pattern, url))
+            })
+            .unwrap_or(&self.default)
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -44,6 +130,13 @@ pub struct Patchwork {
     pub pass: Option<String>,
     pub token: Option<String>,
     pub polling_interval: u64,
+    pub page_size: Option<u64>,
+    pub max_pages: Option<u64>,
+    pub lookback: Option<u64>,
+    pub use_events: Option<bool>,
+    pub retries: Option<u32>,
+    pub retry_delay: Option<u64>,
+    pub max_retry_delay: Option<u64>,
 }
 
 // TODO: make this CI server agnostic (i.e buildbot or whatever)
@@ -56,22 +149,115 @@ pub struct Jenkins {
     pub token: Option<String>,
 }
 
+// Which results from testing a whole series to post on every patch in it
+#[derive(Deserialize, Clone)]
+pub enum SeriesChecks {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "aggregate")]
+    Aggregate,
+    #[serde(rename = "all")]
+    All,
+}
+
+/// A base branch to test patches on.  In the configuration file this is
+/// either just the name of a local branch, or a table that also names the
+/// remote branch to fetch it from, like
+/// `{ name = "master", upstream = "origin/master" }`.
+#[derive(Clone)]
+pub struct Branch {
+    pub name: String,
+    pub upstream: Option<String>,
+}
+
+impl<'de> Deserialize<'de> for Branch {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum BranchConfig {
+            Name(String),
+            Table {
+                name: String,
+                upstream: Option<String>,
+            },
+        }
+
+        Ok(match BranchConfig::deserialize(deserializer)? {
+            BranchConfig::Name(name) => Branch {
+                name: name,
+                upstream: None,
+            },
+            BranchConfig::Table { name, upstream } => Branch {
+                name: name,
+                upstream: upstream,
+            },
+        })
+    }
+}
+
+// Check that a ref template makes valid ref names, so a bad one is caught
+// when the config is loaded rather than when we first push
+fn deserialize_ref_template<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let template = match Option::<String>::deserialize(deserializer)? {
+        Some(template) => template,
+        None => return Ok(None),
+    };
+    let names = RefNames {
+        project: "project".to_string(),
+        patch_id: Some(1),
+        series_id: Some(1),
+        version: Some(1),
+        run: "patch_1".to_string(),
+    };
+    let refname = names.expand(&template, "name", "master");
+    // Anything left in braces isn't something we know how to fill in
+    if refname.contains('{') || !Reference::is_valid_name(&refname) {
+        return Err(de::Error::custom(format!(
+            "ref_template {:?} doesn't make valid ref names, like {:?}",
+            template, refname
+        )));
+    }
+    Ok(Some(template))
+}
+
 #[derive(Deserialize, Clone)]
 pub struct Project {
     pub repository: String,
-    pub branches: Vec<String>,
+    pub clone_url: Option<String>,
+    pub branches: Vec<Branch>,
     pub test_all_branches: Option<bool>,
     pub remote_name: String,
     pub remote_uri: String,
+    #[serde(default, deserialize_with = "deserialize_ref_template")]
+    pub ref_template: Option<String>,
     pub jobs: Vec<Job>,
     pub push_results: bool,
     pub category: Option<String>,
+    pub testable_states: Option<Vec<PatchState>>,
+    pub aggregate_results: Option<bool>,
+    pub series_checks: Option<SeriesChecks>,
+    pub max_jobs: Option<usize>,
+    pub bisect: Option<bool>,
+    pub credentials: Option<Credentials>,
 }
 
 impl Project {
     pub fn get_repo(&self) -> Result<Repository, Error> {
         Repository::open(&self.repository)
     }
+
+    pub fn is_testable(&self, state: &PatchState) -> bool {
+        match self.testable_states {
+            Some(ref states) => states.contains(state),
+            None => *state == PatchState::New || *state == PatchState::UnderReview,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -82,6 +268,12 @@ pub struct Job {
     pub branch: String,
     pub hefty: bool,
     pub warn_on_fail: bool,
+    pub bisect: bool,
+    pub queue_timeout: Option<u64>,
+    pub build_timeout: Option<u64>,
+    pub console_patterns: Vec<Regex>,
+    pub console_lines: usize,
+    pub test_suites: Vec<String>,
     pub parameters: BTreeMap<String, String>,
 }
 
@@ -109,6 +301,12 @@ impl<'de> Deserialize<'de> for Job {
                 let mut branch = None;
                 let mut hefty = None;
                 let mut warn_on_fail = None;
+                let mut bisect = None;
+                let mut queue_timeout = None;
+                let mut build_timeout = None;
+                let mut console_patterns: Option<Vec<String>> = None;
+                let mut console_lines = None;
+                let mut test_suites = None;
                 let mut parameters = BTreeMap::new();
                 while let Some(key) = map.next_key::<String>()? {
                     match key.as_str() {
@@ -148,6 +346,42 @@ impl<'de> Deserialize<'de> for Job {
                             }
                             warn_on_fail = Some(map.next_value()?);
                         }
+                        "bisect" => {
+                            if bisect.is_some() {
+                                return Err(de::Error::duplicate_field("bisect"));
+                            }
+                            bisect = Some(map.next_value()?);
+                        }
+                        "queue_timeout" => {
+                            if queue_timeout.is_some() {
+                                return Err(de::Error::duplicate_field("queue_timeout"));
+                            }
+                            queue_timeout = Some(map.next_value()?);
+                        }
+                        "build_timeout" => {
+                            if build_timeout.is_some() {
+                                return Err(de::Error::duplicate_field("build_timeout"));
+                            }
+                            build_timeout = Some(map.next_value()?);
+                        }
+                        "console_patterns" => {
+                            if console_patterns.is_some() {
+                                return Err(de::Error::duplicate_field("console_patterns"));
+                            }
+                            console_patterns = Some(map.next_value()?);
+                        }
+                        "console_lines" => {
+                            if console_lines.is_some() {
+                                return Err(de::Error::duplicate_field("console_lines"));
+                            }
+                            console_lines = Some(map.next_value()?);
+                        }
+                        "test_suites" => {
+                            if test_suites.is_some() {
+                                return Err(de::Error::duplicate_field("test_suites"));
+                            }
+                            test_suites = Some(map.next_value()?);
+                        }
                         _ => {
                             parameters.insert(key, map.next_value()?);
                         }
@@ -160,6 +394,14 @@ impl<'de> Deserialize<'de> for Job {
                 let title = title.unwrap_or(job.clone());
                 let hefty = hefty.unwrap_or(false);
                 let warn_on_fail = warn_on_fail.unwrap_or(false);
+                let bisect = bisect.unwrap_or(false);
+                let console_patterns = console_patterns
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|pattern| Regex::new(pattern).map_err(de::Error::custom))
+                    .collect::<Result<Vec<Regex>, A::Error>>()?;
+                let console_lines = console_lines.unwrap_or(20);
+                let test_suites = test_suites.unwrap_or_default();
 
                 Ok(Job {
                     job: job,
@@ -168,6 +410,12 @@ impl<'de> Deserialize<'de> for Job {
                     branch: branch,
                     hefty: hefty,
                     warn_on_fail: warn_on_fail,
+                    bisect: bisect,
+                    queue_timeout: queue_timeout,
+                    build_timeout: build_timeout,
+                    console_patterns: console_patterns,
+                    console_lines: console_lines,
+                    test_suites: test_suites,
                     parameters: parameters,
                 })
             }
@@ -177,11 +425,27 @@ impl<'de> Deserialize<'de> for Job {
     }
 }
 
+#[derive(Deserialize, Clone)]
+pub struct State {
+    pub directory: String,
+    pub outbox_interval: Option<u64>,
+    pub report_url: Option<String>,
+    pub ref_ttl: Option<u64>,
+    pub gc_interval: Option<u64>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Scheduler {
+    pub max_jobs: Option<usize>,
+}
+
 #[derive(Deserialize, Clone)]
 pub struct Config {
     pub git: Git,
     pub patchwork: Patchwork,
     pub jenkins: Jenkins,
+    pub state: Option<State>,
+    pub scheduler: Option<Scheduler>,
     pub projects: BTreeMap<String, Project>,
 }
 
@@ -222,9 +486,47 @@ mod test {
         parse("examples/openpower.toml");
     }
 
+    #[test]
+    fn credentials_for_url() {
+        let config = parse("examples/openpower.toml");
+        let github = config
+            .git
+            .credentials_for("https://github.com/ruscur/linux.git");
+        assert_eq!(github.method(), CredentialMethod::UserPass);
+        let default = config.git.credentials_for("git@gitolite.example.com:linux");
+        assert_eq!(default.method(), CredentialMethod::SshKey);
+
+        assert!(url_matches(
+            "https://*.example.com/*.git",
+            "https://git.example.com/linux.git"
+        ));
+        assert!(!url_matches(
+            "https://*.example.com/*.git",
+            "https://git.example.com/linux"
+        ));
+        assert!(!url_matches("git@github.com", "git@github.com:linux"));
+    }
+
     #[test]
     #[should_panic(expected = "Could not parse configuration file, exiting")]
     fn parse_example_invalid() {
         parse("examples/tests/invalid.toml");
     }
+
+    #[test]
+    fn ref_templates() {
+        #[derive(Deserialize)]
+        struct Templated {
+            #[serde(default, deserialize_with = "deserialize_ref_template")]
+            ref_template: Option<String>,
+        }
+        let parse = |config: &str| toml::de::from_str::<Templated>(config).map(|t| t.ref_template);
+
+        assert_eq!(parse("").unwrap(), None);
+        assert!(parse("ref_template = \"refs/snowpatch/{project}/{series_id}/{branch}\"").is_ok());
+        // Not a ref, an unknown placeholder, and something git doesn't allow
+        assert!(parse("ref_template = \"{name}_{branch}\"").is_err());
+        assert!(parse("ref_template = \"refs/heads/{patch}\"").is_err());
+        assert!(parse("ref_template = \"refs/heads/{name}..{branch}\"").is_err());
+    }
 }
@@ -0,0 +1,884 @@
+//
+// snowpatch - continuous integration for patch-based workflows
+//
+// Copyright (C) 2018 IBM Corporation
+// Authors:
+//     Russell Currey <ruscur@russell.cc>
+//     Andrew Donnellan <andrew.donnellan@au1.ibm.com>
+//
+// This program is free software; you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation; either version 2 of the License, or (at your option)
+// any later version.
+//
+// state.rs - record of what snowpatch has tested, where and how it went
+//
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rusqlite::types::Type;
+use rusqlite::{self, Connection, OptionalExtension, Row, TransactionBehavior, NO_PARAMS};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+
+use patchwork::TestResult;
+use utils;
+
+/// What a test run was testing.
+#[derive(Clone, PartialEq, Debug)]
+pub enum RunKey {
+    /// A Patchwork patch, along with the patches before it in its series
+    Patch(u64),
+    /// A whole Patchwork series
+    Series(u64),
+    /// A local mbox file
+    Mbox(String),
+}
+
+impl RunKey {
+    fn parse(key: &str) -> Option<RunKey> {
+        let mut parts = key.splitn(2, '/');
+        match (parts.next(), parts.next()) {
+            (Some("patch"), Some(id)) => id.parse().ok().map(RunKey::Patch),
+            (Some("series"), Some(id)) => id.parse().ok().map(RunKey::Series),
+            (Some("mbox"), Some(name)) => Some(RunKey::Mbox(name.to_string())),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RunKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RunKey::Patch(id) => write!(f, "patch/{}", id),
+            RunKey::Series(id) => write!(f, "series/{}", id),
+            RunKey::Mbox(ref name) => write!(f, "mbox/{}", name),
+        }
+    }
+}
+
+/// A Jenkins build for a branch, recorded before it's queued so that it's
+/// still run if snowpatch stops first.
+#[derive(Clone, Default)]
+pub struct BuildRecord {
+    pub job: String,
+    pub queue_url: Option<String>,
+    pub build_url: Option<String>,
+    // Timestamps are in seconds since the epoch
+    pub queued: Option<u64>,
+    pub started: Option<u64>,
+    pub finished: Option<u64>,
+}
+
+/// A base branch we tried to apply the patches to.
+#[derive(Clone, Default)]
+pub struct BranchRecord {
+    pub branch: String,
+    // The commit the patches were applied to
+    pub base: Option<String>,
+    // The branch we pushed for testing, if the patches applied, or why they
+    // didn't
+    pub tag: Option<String>,
+    pub apply_error: Option<String>,
+    // The file name of the full report of why they didn't apply, if it was
+    // saved
+    pub apply_report: Option<String>,
+    // Where the results go if not to the run's checks URL, when testing a
+    // series a patch at a time
+    pub checks_url: Option<String>,
+    // When the branch was pushed, and when it was deleted from the remote
+    pub pushed: Option<u64>,
+    pub deleted: Option<u64>,
+    pub builds: Vec<BuildRecord>,
+}
+
+/// A ref we pushed for testing that should be deleted from the remote.
+pub struct StaleRef {
+    pub key: RunKey,
+    pub project: Option<String>,
+    pub refname: String,
+}
+
+#[derive(Clone, Default)]
+pub struct TestRecord {
+    // Where the results go, so they can still be reported if snowpatch
+    // restarts part way through
+    pub project: Option<String>,
+    pub checks_url: Option<String>,
+    pub series_checks: Vec<String>,
+    pub started: u64,
+    pub finished: Option<u64>,
+    pub branches: Vec<BranchRecord>,
+    pub results: Vec<TestResult>,
+    // Results for other patches, with the checks URL of each
+    pub patch_results: Vec<(String, TestResult)>,
+}
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS runs (
+        key TEXT PRIMARY KEY,
+        project TEXT,
+        checks_url TEXT,
+        series_checks TEXT NOT NULL,
+        started INTEGER NOT NULL,
+        finished INTEGER,
+        results TEXT NOT NULL,
+        patch_results TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS branches (
+        id INTEGER PRIMARY KEY,
+        run TEXT NOT NULL REFERENCES runs (key) ON DELETE CASCADE,
+        branch TEXT NOT NULL,
+        base TEXT,
+        tag TEXT,
+        apply_error TEXT,
+        apply_report TEXT,
+        checks_url TEXT,
+        pushed INTEGER,
+        deleted INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS branches_run ON branches (run);
+    CREATE TABLE IF NOT EXISTS builds (
+        id INTEGER PRIMARY KEY,
+        branch INTEGER NOT NULL REFERENCES branches (id) ON DELETE CASCADE,
+        job TEXT NOT NULL,
+        queue_url TEXT,
+        build_url TEXT,
+        queued INTEGER,
+        started INTEGER,
+        finished INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS builds_branch ON builds (branch);
+";
+
+// Lists and results are kept as JSON text
+fn to_json<T: Serialize>(value: &T) -> rusqlite::Result<String> {
+    serde_json::to_string(value)
+        .map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))
+}
+
+fn from_json<T: DeserializeOwned>(row: &Row, idx: usize) -> rusqlite::Result<T> {
+    let text: String = row.get(idx)?;
+    serde_json::from_str(&text)
+        .map_err(|err| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(err)))
+}
+
+// Timestamps are kept as SQLite integers, which are signed
+fn get_time(row: &Row, idx: usize) -> rusqlite::Result<Option<u64>> {
+    row.get::<_, Option<i64>>(idx)
+        .map(|time| time.map(|time| time as u64))
+}
+
+fn load_builds(db: &Connection, branch: i64) -> rusqlite::Result<Vec<BuildRecord>> {
+    let mut stmt = db.prepare(
+        "SELECT job, queue_url, build_url, queued, started, finished
+         FROM builds WHERE branch = ? ORDER BY id",
+    )?;
+    let builds = stmt.query_map(&[branch], |row| {
+        Ok(BuildRecord {
+            job: row.get(0)?,
+            queue_url: row.get(1)?,
+            build_url: row.get(2)?,
+            queued: get_time(row, 3)?,
+            started: get_time(row, 4)?,
+            finished: get_time(row, 5)?,
+        })
+    })?;
+    builds.collect()
+}
+
+fn load_branches(db: &Connection, key: &str) -> rusqlite::Result<Vec<BranchRecord>> {
+    let mut stmt = db.prepare(
+        "SELECT id, branch, base, tag, apply_error, apply_report, checks_url, pushed, deleted
+         FROM branches WHERE run = ? ORDER BY id",
+    )?;
+    let branches = stmt
+        .query_map(&[key], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                BranchRecord {
+                    branch: row.get(1)?,
+                    base: row.get(2)?,
+                    tag: row.get(3)?,
+                    apply_error: row.get(4)?,
+                    apply_report: row.get(5)?,
+                    checks_url: row.get(6)?,
+                    pushed: get_time(row, 7)?,
+                    deleted: get_time(row, 8)?,
+                    builds: Vec::new(),
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    branches
+        .into_iter()
+        .map(|(id, mut branch)| {
+            branch.builds = load_builds(db, id)?;
+            Ok(branch)
+        })
+        .collect()
+}
+
+fn load_run(db: &Connection, key: &str) -> rusqlite::Result<Option<TestRecord>> {
+    let run = db
+        .query_row(
+            "SELECT project, checks_url, series_checks, started, finished, results,
+                    patch_results
+             FROM runs WHERE key = ?",
+            &[key],
+            |row| {
+                Ok(TestRecord {
+                    project: row.get(0)?,
+                    checks_url: row.get(1)?,
+                    series_checks: from_json(row, 2)?,
+                    started: get_time(row, 3)?.unwrap_or(0),
+                    finished: get_time(row, 4)?,
+                    branches: Vec::new(),
+                    results: from_json(row, 5)?,
+                    patch_results: from_json(row, 6)?,
+                })
+            },
+        )
+        .optional()?;
+    match run {
+        Some(mut run) => {
+            run.branches = load_branches(db, key)?;
+            Ok(Some(run))
+        }
+        None => Ok(None),
+    }
+}
+
+// The newest branch recorded with this name in a run, which is the one being
+// tested
+fn branch_id(db: &Connection, key: &RunKey, branch: &str) -> rusqlite::Result<Option<i64>> {
+    db.query_row(
+        "SELECT id FROM branches WHERE run = ? AND branch = ? ORDER BY id DESC LIMIT 1",
+        params![key.to_string(), branch],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+// The newest build of a job on that branch
+fn build_id(
+    db: &Connection,
+    key: &RunKey,
+    branch: &str,
+    job: &str,
+) -> rusqlite::Result<Option<i64>> {
+    let branch = match branch_id(db, key, branch)? {
+        Some(branch) => branch,
+        None => return Ok(None),
+    };
+    db.query_row(
+        "SELECT id FROM builds WHERE branch = ? AND job = ? ORDER BY id DESC LIMIT 1",
+        params![branch, job],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Everything we've recorded about test runs, kept in an SQLite database if
+/// we have somewhere to keep it.  Each change only touches the rows it's
+/// about, so other snowpatch processes can use the same database at the
+/// same time.
+pub struct StateStore {
+    path: Option<PathBuf>,
+    db: Mutex<Connection>,
+}
+
+impl StateStore {
+    /// A store that is only kept for as long as snowpatch is running.
+    pub fn in_memory() -> StateStore {
+        let db = Connection::open_in_memory()
+            .unwrap_or_else(|err| panic!("Couldn't create state database: {}", err));
+        StateStore::init(None, db)
+    }
+
+    pub fn open(path: &Path) -> StateStore {
+        let db = Connection::open(path)
+            .unwrap_or_else(|err| panic!("Couldn't open state database {:?}: {}", path, err));
+        StateStore::init(Some(path.to_path_buf()), db)
+    }
+
+    fn init(path: Option<PathBuf>, db: Connection) -> StateStore {
+        // Wait for other processes to finish their changes rather than
+        // failing straight away
+        db.busy_timeout(Duration::from_secs(60))
+            .and_then(|_| db.query_row("PRAGMA journal_mode = WAL", NO_PARAMS, |_| Ok(())))
+            .and_then(|_| db.execute_batch("PRAGMA foreign_keys = ON;"))
+            .and_then(|_| db.execute_batch(SCHEMA))
+            .unwrap_or_else(|err| panic!("Couldn't set up state database {:?}: {}", path, err));
+        StateStore {
+            path: path,
+            db: Mutex::new(db),
+        }
+    }
+
+    // Make a change in a single transaction, taking the database's write lock
+    // before reading anything so that other processes can't change it in
+    // between
+    fn transaction<F>(&self, f: F) -> rusqlite::Result<()>
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<()>,
+    {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        f(&tx)?;
+        tx.commit()
+    }
+
+    // Make a change that it's not worth stopping for if it fails
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<()>,
+    {
+        if let Err(err) = self.transaction(f) {
+            error!("Couldn't update state database {:?}: {}", self.path, err);
+        }
+    }
+
+    fn query<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<T>,
+    {
+        let db = self.db.lock().unwrap();
+        f(&db).unwrap_or_else(|err| panic!("Couldn't read state database {:?}: {}", self.path, err))
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, key: &RunKey) -> Option<TestRecord> {
+        self.query(|db| load_run(db, &key.to_string()))
+    }
+
+    /// Whether we've started testing this before.  Runs that didn't finish
+    /// are picked up again by `unfinished`, so don't need starting again.
+    pub fn contains(&self, key: &RunKey) -> bool {
+        self.query(|db| {
+            db.query_row(
+                "SELECT 1 FROM runs WHERE key = ?",
+                &[&key.to_string()],
+                |_| Ok(()),
+            )
+            .optional()
+        })
+        .is_some()
+    }
+
+    /// Test runs that were started but never finished.
+    pub fn unfinished(&self) -> Vec<(RunKey, TestRecord)> {
+        self.query(|db| {
+            let keys = db
+                .prepare("SELECT key FROM runs WHERE finished IS NULL ORDER BY started")?
+                .query_map(NO_PARAMS, |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let mut runs = Vec::new();
+            for key in keys {
+                if let (Some(run_key), Some(run)) = (RunKey::parse(&key), load_run(db, &key)?) {
+                    runs.push((run_key, run));
+                }
+            }
+            Ok(runs)
+        })
+    }
+
+    /// Forget about a test run, so it will be tested again.
+    pub fn remove(&self, key: &RunKey) {
+        self.update(|db| {
+            db.execute("DELETE FROM runs WHERE key = ?", &[&key.to_string()])
+                .map(|_| ())
+        });
+    }
+
+    /// Start recording a new test run, replacing anything recorded before.
+    /// If the results should be posted to Patchwork, `checks_url` and
+    /// `series_checks` say where.
+    ///
+    /// Until this succeeds, the run can't be picked up again if snowpatch
+    /// stops before it's finished.
+    pub fn start_run(
+        &self,
+        key: &RunKey,
+        project: &str,
+        checks_url: Option<&str>,
+        series_checks: &[String],
+    ) -> rusqlite::Result<()> {
+        self.transaction(|db| {
+            db.execute("DELETE FROM runs WHERE key = ?", &[&key.to_string()])?;
+            db.execute(
+                "INSERT INTO runs (key, project, checks_url, series_checks, started,
+                                   results, patch_results)
+                 VALUES (?, ?, ?, ?, ?, '[]', '[]')",
+                params![
+                    key.to_string(),
+                    project,
+                    checks_url,
+                    to_json(&series_checks)?,
+                    utils::timestamp() as i64
+                ],
+            )
+            .map(|_| ())
+        })
+    }
+
+    fn insert_branch(&self, key: &RunKey, branch: &BranchRecord) {
+        self.update(|db| {
+            let inserted = db.execute(
+                "INSERT INTO branches (run, branch, base, tag, apply_error, checks_url, pushed)
+                 SELECT key, ?, ?, ?, ?, ?, ? FROM runs WHERE key = ?",
+                params![
+                    branch.branch,
+                    branch.base,
+                    branch.tag,
+                    branch.apply_error,
+                    branch.checks_url,
+                    branch.pushed.map(|time| time as i64),
+                    key.to_string()
+                ],
+            )?;
+            if inserted == 0 {
+                return Ok(());
+            }
+            let id = db.last_insert_rowid();
+            for build in &branch.builds {
+                db.execute(
+                    "INSERT INTO builds (branch, job) VALUES (?, ?)",
+                    params![id, build.job],
+                )?;
+            }
+            Ok(())
+        });
+    }
+
+    // The builds we're about to queue
+    fn planned_builds(jobs: &[&str]) -> Vec<BuildRecord> {
+        jobs.iter()
+            .map(|job| BuildRecord {
+                job: job.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Record applying the patches to a branch, with either the branch
+    /// pushed for testing or why they didn't apply, and the `jobs` that are
+    /// going to be run on it.
+    pub fn record_branch(
+        &self,
+        key: &RunKey,
+        branch: &str,
+        base: &str,
+        applied: Result<&str, &str>,
+        jobs: &[&str],
+    ) {
+        self.insert_branch(
+            key,
+            &BranchRecord {
+                branch: branch.to_string(),
+                base: Some(base.to_string()),
+                tag: applied.ok().map(str::to_string),
+                apply_error: applied.err().map(str::to_string),
+                pushed: applied.ok().map(|_| utils::timestamp()),
+                builds: StateStore::planned_builds(jobs),
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Save the full report of why the patches didn't apply to a branch in
+    /// the `reports` directory next to the state database, and return its
+    /// file name.  Reports are only kept if we have somewhere to keep state.
+    pub fn save_report(&self, key: &RunKey, branch: &str, report: &str) -> Option<String> {
+        let dir = self.path.as_ref()?.with_file_name("reports");
+        let name = format!(
+            "{}.txt",
+            utils::sanitise_path(format!("{}_{}", key, branch))
+        );
+        let path = dir.join(&name);
+        let saved = fs::create_dir_all(&dir)
+            .and_then(|_| utils::write_atomically(&path, report.as_bytes()));
+        if let Err(err) = saved {
+            error!("Couldn't save apply report {:?}: {}", path, err);
+            return None;
+        }
+        self.update(|db| {
+            if let Some(id) = branch_id(db, key, branch)? {
+                db.execute(
+                    "UPDATE branches SET apply_report = ? WHERE id = ?",
+                    params![name, id],
+                )?;
+            }
+            Ok(())
+        });
+        Some(name)
+    }
+
+    /// Record a branch pushed to test one patch of a series on its own,
+    /// whose results go to that patch's `checks_url`.
+    pub fn record_patch_branch(
+        &self,
+        key: &RunKey,
+        branch: &str,
+        base: &str,
+        tag: &str,
+        checks_url: &str,
+        jobs: &[&str],
+    ) {
+        self.insert_branch(
+            key,
+            &BranchRecord {
+                branch: branch.to_string(),
+                base: Some(base.to_string()),
+                tag: Some(tag.to_string()),
+                checks_url: Some(checks_url.to_string()),
+                pushed: Some(utils::timestamp()),
+                builds: StateStore::planned_builds(jobs),
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Record that a branch we pushed has been deleted from the remote, or
+    /// couldn't be pushed.  Builds that hadn't been queued for it can't be
+    /// any more, so they're recorded as finished too.
+    pub fn record_ref_deleted(&self, key: &RunKey, refname: &str) {
+        self.update(|db| {
+            let now = utils::timestamp() as i64;
+            db.execute(
+                "UPDATE builds SET finished = ?
+                 WHERE queue_url IS NULL AND finished IS NULL
+                   AND branch IN (SELECT id FROM branches WHERE run = ? AND tag = ?)",
+                params![now, key.to_string(), refname],
+            )?;
+            db.execute(
+                "UPDATE branches SET deleted = ? WHERE run = ? AND tag = ?",
+                params![now, key.to_string(), refname],
+            )
+            .map(|_| ())
+        });
+    }
+
+    /// The refs we've pushed and not deleted that aren't needed any more:
+    /// those for runs that have finished, and those pushed more than `ttl`
+    /// seconds before `now`, in case the run they're for is stuck.  Refs for
+    /// runs still waiting for a Jenkins build are never stale, as the build
+    /// may not have checked them out yet.
+    pub fn stale_refs(&self, ttl: u64, now: u64) -> Vec<StaleRef> {
+        let refs = self.query(|db| {
+            db.prepare(
+                "SELECT runs.key, runs.project, branches.tag, branches.pushed, runs.started,
+                        runs.finished
+                 FROM branches JOIN runs ON branches.run = runs.key
+                 WHERE branches.tag IS NOT NULL AND branches.deleted IS NULL
+                   AND (runs.finished IS NOT NULL OR NOT EXISTS (
+                       SELECT 1 FROM builds JOIN branches AS b ON builds.branch = b.id
+                       WHERE b.run = runs.key AND builds.finished IS NULL
+                         AND COALESCE(builds.started, builds.queued, b.pushed, runs.started)
+                             > ?))
+                 ORDER BY branches.id",
+            )?
+            // Builds that haven't got anywhere within the TTL were left
+            // behind by a run that crashed, and won't finish
+            .query_map(&[now.saturating_sub(ttl) as i64], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    // Runs recorded before we kept track only say when they
+                    // started
+                    get_time(row, 3)?.or(get_time(row, 4)?).unwrap_or(0),
+                    get_time(row, 5)?.is_some(),
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+        });
+        refs.into_iter()
+            .filter(|&(_, _, _, pushed, finished)| finished || now.saturating_sub(pushed) >= ttl)
+            .filter_map(|(key, project, refname, _, _)| {
+                RunKey::parse(&key).map(|key| StaleRef {
+                    key: key,
+                    project: project,
+                    refname: refname,
+                })
+            })
+            .collect()
+    }
+
+    /// Record that a build was queued at `queued`, in seconds since the
+    /// epoch, so its queue timeout still counts from then after a restart.
+    pub fn record_build_queued(
+        &self,
+        key: &RunKey,
+        branch: &str,
+        job: &str,
+        queue_url: &str,
+        queued: u64,
+    ) {
+        self.update(|db| {
+            let id = match branch_id(db, key, branch)? {
+                Some(id) => id,
+                None => return Ok(()),
+            };
+            let planned: Option<i64> = db
+                .query_row(
+                    "SELECT id FROM builds WHERE branch = ? AND job = ? AND queue_url IS NULL
+                     ORDER BY id LIMIT 1",
+                    params![id, job],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let queued = queued as i64;
+            match planned {
+                Some(build) => db.execute(
+                    "UPDATE builds SET queue_url = ?, queued = ? WHERE id = ?",
+                    params![queue_url, queued, build],
+                ),
+                None => db.execute(
+                    "INSERT INTO builds (branch, job, queue_url, queued) VALUES (?, ?, ?, ?)",
+                    params![id, job, queue_url, queued],
+                ),
+            }
+            .map(|_| ())
+        });
+    }
+
+    /// Record that a build started at `started`, as for `record_build_queued`.
+    pub fn record_build_started(
+        &self,
+        key: &RunKey,
+        branch: &str,
+        job: &str,
+        build_url: &str,
+        started: u64,
+    ) {
+        self.update(|db| {
+            if let Some(id) = build_id(db, key, branch, job)? {
+                db.execute(
+                    "UPDATE builds SET build_url = ?, started = ? WHERE id = ?",
+                    params![build_url, started as i64, id],
+                )?;
+            }
+            Ok(())
+        });
+    }
+
+    pub fn record_build_finished(&self, key: &RunKey, branch: &str, job: &str) {
+        self.update(|db| {
+            if let Some(id) = build_id(db, key, branch, job)? {
+                db.execute(
+                    "UPDATE builds SET finished = ? WHERE id = ?",
+                    params![utils::timestamp() as i64, id],
+                )?;
+            }
+            Ok(())
+        });
+    }
+
+    pub fn finish_run(
+        &self,
+        key: &RunKey,
+        results: &[TestResult],
+        patch_results: &[(String, TestResult)],
+    ) {
+        self.update(|db| {
+            db.execute(
+                "UPDATE runs SET finished = ?, results = ?, patch_results = ? WHERE key = ?",
+                params![
+                    utils::timestamp() as i64,
+                    to_json(&results)?,
+                    to_json(&patch_results)?,
+                    key.to_string()
+                ],
+            )
+            .map(|_| ())
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use state::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn records_survive_reopening() {
+        let dir = TempDir::new("snowpatch").unwrap();
+        let path = dir.path().join("tested.db");
+        let key = RunKey::Patch(42);
+
+        let store = StateStore::open(&path);
+        assert!(!store.contains(&key));
+        store
+            .start_run(
+                &key,
+                "linux",
+                Some("https://patchwork/api/patches/42/checks/"),
+                &[],
+            )
+            .unwrap();
+        store.record_branch(
+            &key,
+            "master",
+            "0123abcd",
+            Ok("42_master"),
+            &["build", "test"],
+        );
+        let queue_url = "https://jenkins/queue/item/1/";
+        store.record_build_queued(&key, "master", "build", queue_url, 1000);
+        let build_url = "https://jenkins/job/build/1/";
+        store.record_build_started(&key, "master", "build", build_url, 1060);
+        store.record_build_finished(&key, "master", "build");
+        store.record_branch(&key, "next", "4567cdef", Ok("42_next"), &["build"]);
+
+        // As if snowpatch had restarted part way through
+        let store = StateStore::open(&path);
+        assert!(store.contains(&key));
+        assert!(!store.contains(&RunKey::Series(42)));
+        let unfinished = store.unfinished();
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(unfinished[0].0, key);
+        let run = &unfinished[0].1;
+        assert_eq!(run.project, Some("linux".to_string()));
+        assert_eq!(run.branches[0].tag, Some("42_master".to_string()));
+        let build = &run.branches[0].builds[0];
+        assert_eq!(build.build_url, Some(build_url.to_string()));
+        // Timeouts carry on from when the build was queued and started
+        assert_eq!(build.queued, Some(1000));
+        assert_eq!(build.started, Some(1060));
+        // Jobs that weren't queued yet are still there to be queued
+        let build = &run.branches[0].builds[1];
+        assert_eq!(build.job, "test");
+        assert_eq!(build.queue_url, None);
+        assert_eq!(build.finished, None);
+
+        // Unless the branch they were for is gone
+        store.record_ref_deleted(&key, "42_next");
+        let run = store.get(&key).unwrap();
+        assert_eq!(run.branches[0].builds[1].finished, None);
+        assert!(run.branches[1].builds[0].finished.is_some());
+
+        store.finish_run(&key, &[TestResult::default()], &[]);
+        assert!(store.unfinished().is_empty());
+        assert_eq!(store.get(&key).unwrap().results.len(), 1);
+    }
+
+    #[test]
+    fn queued_runs_survive_restart() {
+        let dir = TempDir::new("snowpatch").unwrap();
+        let path = dir.path().join("tested.db");
+        let key = RunKey::Patch(42);
+
+        let store = StateStore::open(&path);
+        store
+            .start_run(
+                &key,
+                "linux",
+                Some("https://patchwork/api/patches/42/checks/"),
+                &[],
+            )
+            .unwrap();
+
+        // As if snowpatch had stopped while the run was waiting for its turn,
+        // after polling past the patch
+        let store = StateStore::open(&path);
+        assert!(store.contains(&key));
+        let unfinished = store.unfinished();
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(unfinished[0].0, key);
+        assert!(unfinished[0].1.branches.is_empty());
+        assert_eq!(
+            unfinished[0].1.checks_url,
+            Some("https://patchwork/api/patches/42/checks/".to_string())
+        );
+
+        // If a run can't be recorded, we're told so it isn't polled past
+        store
+            .db
+            .lock()
+            .unwrap()
+            .execute_batch("PRAGMA query_only = ON;")
+            .unwrap();
+        assert!(store
+            .start_run(&RunKey::Patch(43), "linux", None, &[])
+            .is_err());
+        assert!(!store.contains(&RunKey::Patch(43)));
+    }
+
+    #[test]
+    fn stores_share_records() {
+        let dir = TempDir::new("snowpatch").unwrap();
+        let path = dir.path().join("tested.db");
+        let daemon = StateStore::open(&path);
+        let gc = StateStore::open(&path);
+        let key = RunKey::Patch(42);
+
+        daemon.start_run(&key, "linux", None, &[]).unwrap();
+        daemon.record_branch(&key, "master", "0123abcd", Ok("42_master"), &[]);
+        daemon.record_branch(&key, "next", "4567cdef", Ok("42_next"), &[]);
+        // Neither store's changes undo the other's
+        gc.record_ref_deleted(&key, "42_master");
+        daemon.record_build_queued(&key, "next", "build", "https://jenkins/queue/item/1/", 1000);
+
+        let run = StateStore::open(&path).get(&key).unwrap();
+        assert!(run.branches[0].deleted.is_some());
+        assert!(run.branches[1].deleted.is_none());
+        assert_eq!(run.branches[1].builds.len(), 1);
+        assert!(gc.contains(&key));
+    }
+
+    #[test]
+    fn stale_refs() {
+        let store = StateStore::in_memory();
+        let finished = RunKey::Patch(1);
+        let running = RunKey::Patch(2);
+        store.start_run(&finished, "linux", None, &[]).unwrap();
+        store.record_branch(&finished, "master", "0123abcd", Ok("1_master"), &[]);
+        store.record_branch(&finished, "next", "4567cdef", Ok("1_next"), &[]);
+        store.record_ref_deleted(&finished, "1_next");
+        store.finish_run(&finished, &[], &[]);
+        store.start_run(&running, "linux", None, &[]).unwrap();
+        store.record_branch(&running, "master", "0123abcd", Ok("2_master"), &[]);
+        store.record_branch(
+            &running,
+            "next",
+            "4567cdef",
+            Err("Patch failed to apply"),
+            &[],
+        );
+
+        let now = utils::timestamp();
+        let stale = store.stale_refs(3600, now);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].key, finished);
+        assert_eq!(stale[0].refname, "1_master");
+        assert_eq!(store.stale_refs(3600, now + 3600).len(), 2);
+
+        // A run waiting for Jenkins keeps all its refs while the build gets
+        // somewhere
+        let queue_url = "https://jenkins/queue/item/1/";
+        store.record_build_queued(&running, "master", "build", queue_url, now + 1800);
+        let build_url = "https://jenkins/job/build/1/";
+        store.record_build_started(&running, "master", "build", build_url, now + 3000);
+        let stale = store.stale_refs(3600, now + 3600);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].key, finished);
+        assert_eq!(store.stale_refs(3600, now + 7000).len(), 2);
+        store.record_build_finished(&running, "master", "build");
+        assert_eq!(store.stale_refs(3600, now + 3600).len(), 2);
+
+        // Builds a crashed run never queued don't keep its refs for ever
+        let abandoned = RunKey::Patch(3);
+        store.start_run(&abandoned, "linux", None, &[]).unwrap();
+        store.record_branch(&abandoned, "master", "0123abcd", Ok("3_master"), &["build"]);
+        assert_eq!(store.stale_refs(3600, now + 1800).len(), 1);
+        let stale = store.stale_refs(3600, now + 3700);
+        assert_eq!(stale.len(), 3);
+        assert_eq!(stale[2].key, abandoned);
+    }
+}
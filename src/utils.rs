@@ -13,6 +13,89 @@
 // utils.rs - snowpatch generic helpers
 //
 
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The current time in seconds since the epoch.
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/// Format a time in seconds since the epoch the way Patchwork writes dates,
+/// in UTC, e.g. 2018-07-11T04:21:05.
+pub fn iso_date(timestamp: u64) -> String {
+    let (days, secs) = (timestamp / 86_400, timestamp % 86_400);
+    // Howard Hinnant's civil_from_days, counting from 0000-03-01 so leap
+    // days fall at the end of the year
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let (year, month) = if month < 10 {
+        (era * 400 + year_of_era, month + 3)
+    } else {
+        (era * 400 + year_of_era + 1, month - 9)
+    };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Parse a date as Patchwork writes them, in UTC, to seconds since the
+/// epoch.  Fractions of a second are ignored.
+pub fn parse_iso_date(date: &str) -> Option<u64> {
+    let field = |start: usize, end: usize| date.get(start..end).and_then(|f| f.parse::<u64>().ok());
+    let (year, month, day) = (field(0, 4)?, field(5, 7)?, field(8, 10)?);
+    let (hour, minute, second) = (field(11, 13)?, field(14, 16)?, field(17, 19)?);
+    if year < 1970 || month < 1 || month > 12 || day < 1 || day > 31 {
+        return None;
+    }
+    // The inverse of iso_date: days_from_civil, with years starting in March
+    let year = if month <= 2 { year - 1 } else { year };
+    let (era, year_of_era) = (year / 400, year % 400);
+    let month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+    Some(days * 86_400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Replace a file with new contents, so that after a crash it has either the
+/// old contents or the new ones, never a mix or nothing.  The contents are
+/// written to a temporary file next to it, which is synced to disk before
+/// being renamed over it.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    // Make sure the rename itself is on disk too
+    match path.parent() {
+        Some(dir) if dir != Path::new("") => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
 pub fn sanitise_path(path: String) -> String {
     path.replace("/", "_")
         .replace("\\", "_")
@@ -29,3 +112,69 @@ pub fn sanitise_path(path: String) -> String {
         .replace("*", "_")
         .replace("?", "_")
 }
+
+/// Cut text down to at most `max` bytes, ending at a line break if there is
+/// one, and note that it's been cut.
+pub fn excerpt(text: &str, max: usize) -> String {
+    let marker = "\n[...]";
+    if text.len() <= max {
+        return text.to_string();
+    }
+    let mut end = max.saturating_sub(marker.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    if let Some(newline) = text[..end].rfind('\n') {
+        end = newline;
+    }
+    format!("{}{}", &text[..end], marker)
+}
+
+/// Like `excerpt`, but keep the end of the text rather than the start, for
+/// things like logs where what happened last matters most.
+pub fn excerpt_tail(text: &str, max: usize) -> String {
+    let marker = "[...]\n";
+    if text.len() <= max {
+        return text.to_string();
+    }
+    let mut start = text.len() - max.saturating_sub(marker.len());
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    if let Some(newline) = text[start..].find('\n') {
+        start += newline + 1;
+    }
+    format!("{}{}", marker, &text[start..])
+}
+
+#[cfg(test)]
+mod test {
+    use utils::*;
+
+    #[test]
+    fn iso_dates() {
+        assert_eq!(iso_date(0), "1970-01-01T00:00:00");
+        assert_eq!(iso_date(1_531_282_865), "2018-07-11T04:21:05");
+        assert_eq!(iso_date(951_868_799), "2000-02-29T23:59:59");
+        assert_eq!(iso_date(951_868_800), "2000-03-01T00:00:00");
+        for &time in &[0, 1_531_282_865, 951_868_799, 951_868_800] {
+            assert_eq!(parse_iso_date(&iso_date(time)), Some(time));
+        }
+        assert_eq!(
+            parse_iso_date("2018-07-11T04:21:05.123456"),
+            Some(1_531_282_865)
+        );
+        assert_eq!(parse_iso_date("yesterday"), None);
+    }
+
+    #[test]
+    fn excerpts() {
+        let text = "first line\nsecond line\nthird line";
+        assert_eq!(excerpt(text, 100), text);
+        assert_eq!(excerpt(text, 20), "first line\n[...]");
+        assert_eq!(excerpt_tail(text, 100), text);
+        assert_eq!(excerpt_tail(text, 20), "[...]\nthird line");
+        // Lines too long to keep whole are cut, but not within a character
+        assert_eq!(excerpt_tail("ééééé", 9), "[...]\né");
+    }
+}